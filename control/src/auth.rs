@@ -0,0 +1,133 @@
+//! The authentication of the bootloader command. The firmware prints a
+//! challenge, the host answers with its MAC under the key both share: the
+//! SipHash-2-4 of the 8 bytes of the challenge, big endian, keyed with the
+//! 16 bytes of the key. Both numbers are written as 16 hex digits.
+//!
+//! The challenges are derived from a random seed the firmware gathers at boot,
+//! chained through the same keyed hash together with the startup counter. So
+//! they can not be predicted without the key and do not repeat, even if the
+//! seed carries little entropy. A challenge hashes 12 bytes, a response 8, the
+//! length is part of the hash, so one can not be passed off as the other.
+
+/// The length of a key in bytes.
+pub const KEY_LENGTH: usize = 16;
+
+pub type Key = [u8; KEY_LENGTH];
+
+/// Parses a key of 32 hex digits, e.g. from an environment variable at build
+/// time. Anything else panics, which fails the build in a constant.
+pub const fn parse_key(hex: &str) -> Key {
+    let hex = hex.as_bytes();
+    assert!(
+        hex.len() == 2 * KEY_LENGTH,
+        "The key has to be 32 hex digits."
+    );
+
+    let mut key = [0_u8; KEY_LENGTH];
+    let mut index = 0;
+    while index < KEY_LENGTH {
+        key[index] = hex_digit(hex[2 * index]) << 4 | hex_digit(hex[2 * index + 1]);
+        index += 1;
+    }
+    key
+}
+
+const fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        b'A'..=b'F' => digit - b'A' + 10,
+        _ => panic!("The key has to be 32 hex digits."),
+    }
+}
+
+/// The challenge after `previous`, which is the seed for the first one of a boot.
+pub fn next_challenge(key: &Key, previous: u64, startups: u32) -> u64 {
+    let mut message = [0_u8; 12];
+    message[..8].copy_from_slice(&previous.to_be_bytes());
+    message[8..].copy_from_slice(&startups.to_be_bytes());
+    siphash(key, &message)
+}
+
+/// The answer to a bootloader challenge.
+pub fn response(key: &Key, challenge: u64) -> u64 {
+    siphash(key, &challenge.to_be_bytes())
+}
+
+/// SipHash-2-4 with its 64 bit output, the words are little endian.
+pub fn siphash(key: &Key, message: &[u8]) -> u64 {
+    let word = |bytes: &[u8]| {
+        let mut word = [0_u8; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(word)
+    };
+    let k0 = word(&key[..8]);
+    let k1 = word(&key[8..]);
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+
+    let blocks = message.chunks_exact(8);
+    // The length modulo 256 goes into the top byte of the last block.
+    let last = word(blocks.remainder()) | (message.len() as u64) << 56;
+    for block in blocks {
+        compress(&mut v, word(block));
+    }
+    compress(&mut v, last);
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Two rounds per block of the message.
+fn compress(v: &mut [u64; 4], block: u64) {
+    v[3] ^= block;
+    sip_round(v);
+    sip_round(v);
+    v[0] ^= block;
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_vectors() {
+        // The key 00 01 .. 0F and the messages 00 01 .. of the SipHash paper.
+        let key = parse_key("000102030405060708090a0b0c0d0e0f");
+        let message: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash(&key, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash(&key, &message[..1]), 0x74f8_39c5_93dc_67fd);
+        assert_eq!(siphash(&key, &message), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn challenges_depend_on_the_key_and_the_boot() {
+        let key = parse_key("00112233445566778899AABBCCDDEEFF");
+        let other = parse_key("00112233445566778899AABBCCDDEEFE");
+        let first = next_challenge(&key, 1234, 7);
+        assert_ne!(first, next_challenge(&other, 1234, 7));
+        assert_ne!(first, next_challenge(&key, 1234, 8));
+        assert_ne!(first, next_challenge(&key, first, 7));
+        assert_ne!(response(&key, first), response(&other, first));
+    }
+}
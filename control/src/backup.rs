@@ -0,0 +1,516 @@
+//! Export and import of all persisted settings: the configuration block, the
+//! profiles and the boot profile. They are packed into one blob with a magic
+//! number, a format version and a CRC, so a damaged or foreign blob is refused
+//! before any setting changes. The counters and the parked state are no settings
+//! and stay untouched. The firmware has no periodic error correction table yet,
+//! a later format version can append one.
+//!
+//! The blob consists of:
+//! - the magic number and the format version,
+//! - the slot of the boot profile, 0xFF if there is none,
+//! - a flag, followed by the configuration block as stored in the EEPROM if
+//!   there is one (version 1 has no flag and always carries the block),
+//! - for every profile slot a flag, followed by the name and the
+//!   configuration block if the slot is used,
+//! - the CRC of everything in front of it.
+//!
+//! The configuration blocks carry their own layout version, so a blob of an
+//! older firmware is migrated on the next start just like the EEPROM itself.
+//!
+//! Neither side holds the whole blob in RAM. The export reads the stored
+//! records one by one and passes them on, without migrating anything. The
+//! import writes every record straight into the buffer slot that is not in
+//! use, see `eeprom::Staging`. The CRC of each block is held back and its
+//! complement written instead, so a staged block does not count even where
+//! there is no current one. Only when the whole blob and its CRC have arrived
+//! are the CRCs of the blocks written and the staged slots made current.
+
+use crate::config::{Config, PROFILE_NAME_LENGTH};
+use crate::eeprom::{self, crc16_update, Staging, BLOCK_HEADER_LENGTH, CRC16_INIT, PROFILE_SLOTS};
+use crate::hardware::Storage;
+
+const BACKUP_MAGIC: [u8; 2] = *b"EX";
+pub const BACKUP_VERSION: u8 = 2;
+/// The configuration block was always there, without a flag.
+const VERSION_WITHOUT_CONFIG_FLAG: u8 = 1;
+const HEADER_LENGTH: usize = 3;
+const CRC_LENGTH: usize = 2;
+const NO_BOOT_PROFILE: u8 = 0xFF;
+const SLOT_EMPTY: u8 = 0;
+const SLOT_USED: u8 = 1;
+/// The configuration and the profiles.
+const RECORDS: usize = 1 + PROFILE_SLOTS as usize;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BackupError {
+    /// A record does not fit into its slot.
+    TooLong,
+    /// The blob was written by a newer firmware.
+    Version,
+    /// The magic number, the CRC or the structure is wrong, or a staged
+    /// record was overwritten before the import was finished.
+    Corrupted,
+}
+
+impl BackupError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackupError::TooLong => "too long",
+            BackupError::Version => "unknown version",
+            BackupError::Corrupted => "corrupted",
+        }
+    }
+}
+
+/// Passes the blob of the settings stored in the EEPROM to `write`, piece by
+/// piece. Nothing is written, a missing configuration is marked as such.
+pub fn export<S: Storage>(storage: &S, write: &mut dyn FnMut(&[u8])) {
+    let mut crc = CRC16_INIT;
+    let mut emit = |bytes: &[u8]| {
+        crc = crc16_update(crc, bytes);
+        write(bytes);
+    };
+
+    emit(&BACKUP_MAGIC);
+    emit(&[BACKUP_VERSION]);
+    emit(&[eeprom::read_boot_profile(storage).unwrap_or(NO_BOOT_PROFILE)]);
+    match eeprom::read_config(storage) {
+        Some(config) => {
+            emit(&[SLOT_USED]);
+            emit(&eeprom::encode_config(&config));
+        }
+        None => emit(&[SLOT_EMPTY]),
+    }
+
+    for slot in 0..PROFILE_SLOTS {
+        match eeprom::read_profile(slot, storage) {
+            Some((name, config)) => {
+                emit(&[SLOT_USED]);
+                emit(&name.0);
+                emit(&eeprom::encode_config(&config));
+            }
+            None => emit(&[SLOT_EMPTY]),
+        }
+    }
+
+    write(&crc.to_be_bytes());
+}
+
+/// Where the next byte of the blob belongs.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Part {
+    Header,
+    BootProfile,
+    ConfigFlag,
+    ProfileFlag(u8),
+    Name(u8),
+    /// The configuration block of the record, see `Import::staged`.
+    Block(usize),
+    Crc,
+    End,
+}
+
+/// A running import. It only keeps the position in the blob and the CRCs,
+/// the records go to their staging slots as they arrive.
+pub struct Import {
+    part: Part,
+    /// The bytes of the part received so far.
+    offset: usize,
+    header: [u8; HEADER_LENGTH],
+    boot_profile: Option<u8>,
+    /// The length of the current block, known once its header arrived.
+    block_length: usize,
+    /// The slot of the configuration first, then the ones of the profiles.
+    /// A record that is empty in the blob has none.
+    staged: [Option<Staging>; RECORDS],
+    /// The bytes written to each staging slot.
+    staged_lengths: [u8; RECORDS],
+    /// The CRCs held back from the blocks.
+    block_crcs: [[u8; CRC_LENGTH]; RECORDS],
+    /// Of everything written to the staging slots, so a write in between
+    /// that reused one of them is noticed.
+    staged_crc: u16,
+    crc: u16,
+    received_crc: [u8; CRC_LENGTH],
+    /// The first error stops the import, it is reported on commit.
+    error: Option<BackupError>,
+}
+
+impl Import {
+    pub fn new() -> Self {
+        Self {
+            part: Part::Header,
+            offset: 0,
+            header: [0; HEADER_LENGTH],
+            boot_profile: None,
+            block_length: 0,
+            staged: [None; RECORDS],
+            staged_lengths: [0; RECORDS],
+            block_crcs: [[0; CRC_LENGTH]; RECORDS],
+            staged_crc: CRC16_INIT,
+            crc: CRC16_INIT,
+            received_crc: [0; CRC_LENGTH],
+            error: None,
+        }
+    }
+
+    /// Takes the next part of the blob.
+    pub fn append<S: Storage>(&mut self, bytes: &[u8], storage: &mut S) {
+        for byte in bytes {
+            if self.error.is_some() {
+                return;
+            }
+            if let Err(error) = self.push(*byte, storage) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    /// Checks the whole blob and whether the staging slots still hold it, and
+    /// only then makes it the stored settings. Returns the configuration that
+    /// is stored now, none if the blob has none.
+    pub fn commit<S: Storage>(&self, storage: &mut S) -> Result<Option<Config>, BackupError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        if self.part != Part::End || u16::from_be_bytes(self.received_crc) != self.crc {
+            return Err(BackupError::Corrupted);
+        }
+        // Booting an empty profile would silently fall back to the settings.
+        if let Some(slot) = self.boot_profile {
+            if self.staged[1 + slot as usize].is_none() {
+                return Err(BackupError::Corrupted);
+            }
+        }
+
+        let mut crc = CRC16_INIT;
+        let mut buffer = [0_u8; Staging::ROOM];
+        for (staging, length) in self.staged.iter().zip(self.staged_lengths.iter()) {
+            if let Some(staging) = staging {
+                let data = &mut buffer[..*length as usize];
+                storage.read(staging.address(), data);
+                crc = crc16_update(crc, data);
+            }
+        }
+        if crc != self.staged_crc {
+            return Err(BackupError::Corrupted);
+        }
+
+        for (record, staging) in self.staged.iter().enumerate() {
+            if let Some(staging) = staging {
+                let end = staging.address() + self.staged_lengths[record] as u16;
+                storage.write(end - CRC_LENGTH as u16, &self.block_crcs[record]);
+            }
+        }
+        match self.staged[0] {
+            Some(staging) => staging.commit(storage),
+            None => eeprom::clear_config(storage),
+        }
+        for slot in 0..PROFILE_SLOTS {
+            match self.staged[1 + slot as usize] {
+                Some(staging) => staging.commit(storage),
+                None => eeprom::clear_profile(slot, storage),
+            }
+        }
+        eeprom::write_boot_profile(self.boot_profile, storage);
+
+        Ok(eeprom::read_config(storage))
+    }
+
+    fn push<S: Storage>(&mut self, byte: u8, storage: &mut S) -> Result<(), BackupError> {
+        if self.part != Part::Crc {
+            self.crc = crc16_update(self.crc, &[byte]);
+        }
+        let offset = self.offset;
+        self.offset += 1;
+
+        let next = match self.part {
+            Part::Header => {
+                self.header[offset] = byte;
+                if self.offset < HEADER_LENGTH {
+                    return Ok(());
+                }
+                if self.header[..2] != BACKUP_MAGIC {
+                    return Err(BackupError::Corrupted);
+                }
+                if !(VERSION_WITHOUT_CONFIG_FLAG..=BACKUP_VERSION).contains(&self.header[2]) {
+                    return Err(BackupError::Version);
+                }
+                Part::BootProfile
+            }
+
+            Part::BootProfile => {
+                self.boot_profile = match byte {
+                    NO_BOOT_PROFILE => None,
+                    slot if slot < PROFILE_SLOTS => Some(slot),
+                    _ => return Err(BackupError::Corrupted),
+                };
+                if self.header[2] == VERSION_WITHOUT_CONFIG_FLAG {
+                    self.staged[0] = Some(eeprom::stage_config(storage));
+                    Part::Block(0)
+                } else {
+                    Part::ConfigFlag
+                }
+            }
+
+            Part::ConfigFlag => match byte {
+                SLOT_USED => {
+                    self.staged[0] = Some(eeprom::stage_config(storage));
+                    Part::Block(0)
+                }
+                SLOT_EMPTY => Part::ProfileFlag(0),
+                _ => return Err(BackupError::Corrupted),
+            },
+
+            Part::ProfileFlag(slot) => match byte {
+                SLOT_USED => {
+                    self.staged[1 + slot as usize] = Some(eeprom::stage_profile(slot, storage));
+                    Part::Name(slot)
+                }
+                SLOT_EMPTY => after_profile(slot),
+                _ => return Err(BackupError::Corrupted),
+            },
+
+            Part::Name(slot) => {
+                self.stage(1 + slot as usize, byte, storage)?;
+                if self.offset < PROFILE_NAME_LENGTH {
+                    return Ok(());
+                }
+                Part::Block(1 + slot as usize)
+            }
+
+            Part::Block(record) => {
+                let written = if self.offset > BLOCK_HEADER_LENGTH
+                    && self.offset + CRC_LENGTH > self.block_length
+                {
+                    let index = self.offset + CRC_LENGTH - self.block_length - 1;
+                    self.block_crcs[record][index] = byte;
+                    !byte
+                } else {
+                    byte
+                };
+                self.stage(record, written, storage)?;
+                if self.offset == BLOCK_HEADER_LENGTH {
+                    // The header of the block tells its length, it is read back.
+                    let address = self.staged_address(record).ok_or(BackupError::Corrupted)?;
+                    let end = address + self.staged_lengths[record] as u16;
+                    let mut header = [0_u8; BLOCK_HEADER_LENGTH];
+                    storage.read(end - BLOCK_HEADER_LENGTH as u16, &mut header);
+                    self.block_length =
+                        eeprom::block_length(&header).ok_or(BackupError::Corrupted)?;
+                }
+                if self.offset < BLOCK_HEADER_LENGTH || self.offset < self.block_length {
+                    return Ok(());
+                }
+
+                // Checked with the CRC it is going to get.
+                let staging = self.staged[record].ok_or(BackupError::Corrupted)?;
+                let mut buffer = [0_u8; Staging::ROOM];
+                let block = &mut buffer[..self.block_length];
+                storage.read(staging.block_address(), block);
+                block[self.block_length - CRC_LENGTH..].copy_from_slice(&self.block_crcs[record]);
+                eeprom::decode_config(block).ok_or(BackupError::Corrupted)?;
+                match record {
+                    0 => Part::ProfileFlag(0),
+                    profile => after_profile(profile as u8 - 1),
+                }
+            }
+
+            Part::Crc => {
+                self.received_crc[offset] = byte;
+                if self.offset < CRC_LENGTH {
+                    return Ok(());
+                }
+                Part::End
+            }
+
+            Part::End => return Err(BackupError::Corrupted),
+        };
+
+        self.part = next;
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn staged_address(&self, record: usize) -> Option<u16> {
+        self.staged[record].map(|staging| staging.address())
+    }
+
+    /// Writes the next byte of the record to its staging slot.
+    fn stage<S: Storage>(
+        &mut self,
+        record: usize,
+        byte: u8,
+        storage: &mut S,
+    ) -> Result<(), BackupError> {
+        let address = self.staged_address(record).ok_or(BackupError::Corrupted)?;
+        let length = self.staged_lengths[record];
+        if length as usize >= Staging::ROOM {
+            return Err(BackupError::TooLong);
+        }
+
+        storage.write(address + length as u16, &[byte]);
+        self.staged_lengths[record] = length + 1;
+        self.staged_crc = crc16_update(self.staged_crc, &[byte]);
+        Ok(())
+    }
+}
+
+impl Default for Import {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn after_profile(slot: u8) -> Part {
+    if slot + 1 < PROFILE_SLOTS {
+        Part::ProfileFlag(slot + 1)
+    } else {
+        Part::Crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProfileName;
+    use crate::eeprom::crc16;
+    use crate::mock::MockStorage;
+    use crate::startup::PowerOn;
+
+    fn configured_storage() -> MockStorage {
+        let mut storage = MockStorage::default();
+        let config = Config {
+            trim_ppm: 120,
+            travel_limit: Some(40_000),
+            ..Config::default()
+        };
+        eeprom::write_config(&config, &mut storage);
+
+        let profile = Config {
+            power_on: PowerOn::Hold,
+            microsteps: 8,
+            ..config
+        };
+        eeprom::write_profile(2, &ProfileName::new("barndoor"), &profile, &mut storage);
+        eeprom::write_boot_profile(Some(2), &mut storage);
+        storage
+    }
+
+    fn exported(storage: &MockStorage) -> Vec<u8> {
+        let mut blob = Vec::new();
+        export(storage, &mut |bytes| blob.extend_from_slice(bytes));
+        blob
+    }
+
+    fn import(blob: &[u8], storage: &mut MockStorage) -> Result<Option<Config>, BackupError> {
+        let mut import = Import::new();
+        for chunk in blob.chunks(24) {
+            import.append(chunk, storage);
+        }
+        import.commit(storage)
+    }
+
+    #[test]
+    fn settings_are_restored_on_another_unit() {
+        let storage = configured_storage();
+        let blob = exported(&storage);
+
+        let mut other = MockStorage::default();
+        eeprom::write_profile(0, &ProfileName::new("old"), &Config::default(), &mut other);
+
+        let config = import(&blob, &mut other).unwrap().unwrap();
+        assert_eq!(config.trim_ppm, 120);
+        assert_eq!(eeprom::load_config(&mut other).0, config);
+        assert_eq!(eeprom::read_profile(0, &other), None);
+        assert_eq!(
+            eeprom::read_profile(2, &other),
+            eeprom::read_profile(2, &storage)
+        );
+        assert_eq!(eeprom::read_boot_profile(&other), Some(2));
+    }
+
+    #[test]
+    fn export_writes_nothing_and_keeps_a_missing_config_missing() {
+        let mut storage = MockStorage::default();
+        eeprom::write_profile(
+            1,
+            &ProfileName::new("eq40"),
+            &Config::default(),
+            &mut storage,
+        );
+        let before = storage.data;
+        let blob = exported(&storage);
+        assert_eq!(storage.data[..], before[..]);
+
+        let mut other = configured_storage();
+        assert_eq!(import(&blob, &mut other), Ok(None));
+        assert_eq!(eeprom::read_config(&other), None);
+        assert_eq!(eeprom::load_config(&mut other).0, Config::default());
+        assert!(eeprom::read_profile(1, &other).is_some());
+    }
+
+    #[test]
+    fn damaged_blobs_are_refused() {
+        let blob = exported(&configured_storage());
+        let mut other = MockStorage::default();
+
+        let mut damaged = blob.clone();
+        damaged[10] ^= 0x01;
+        assert_eq!(import(&damaged, &mut other), Err(BackupError::Corrupted));
+        assert_eq!(import(&blob[..40], &mut other), Err(BackupError::Corrupted));
+        let mut longer = blob.clone();
+        longer.push(0);
+        assert_eq!(import(&longer, &mut other), Err(BackupError::Corrupted));
+
+        assert_eq!(eeprom::read_config(&other), None);
+        assert_eq!(eeprom::read_profile(2, &other), None);
+        assert_eq!(eeprom::read_boot_profile(&other), None);
+    }
+
+    #[test]
+    fn writes_during_the_import_spoil_it() {
+        let blob = exported(&configured_storage());
+        let mut other = MockStorage::default();
+        eeprom::write_config(&Config::default(), &mut other);
+
+        let mut import = Import::new();
+        import.append(&blob, &mut other);
+        // Saving the settings reuses the staging slot of the configuration.
+        let saved = Config {
+            trim_ppm: -30,
+            ..Config::default()
+        };
+        eeprom::write_config(&saved, &mut other);
+
+        assert_eq!(import.commit(&mut other), Err(BackupError::Corrupted));
+        assert_eq!(eeprom::read_config(&other), Some(saved));
+    }
+
+    #[test]
+    fn blobs_of_the_first_version_are_imported() {
+        let config = Config {
+            trim_ppm: 55,
+            ..Config::default()
+        };
+        let mut blob = vec![b'E', b'X', 1, NO_BOOT_PROFILE];
+        blob.extend_from_slice(&eeprom::encode_config(&config));
+        blob.extend_from_slice(&[SLOT_EMPTY; PROFILE_SLOTS as usize]);
+        let crc = crc16(&blob);
+        blob.extend_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(import(&blob, &mut MockStorage::default()), Ok(Some(config)));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut blob = vec![b'E', b'X', BACKUP_VERSION + 1];
+        let crc = crc16(&blob);
+        blob.extend_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(
+            import(&blob, &mut MockStorage::default()),
+            Err(BackupError::Version)
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! The settings of the platform. They are stored together as one block in the
+//! EEPROM, see the `eeprom` module. Whenever the stored block is missing or
+//! invalid, the compiled-in defaults are used instead.
+
+use embedded_time::duration::*;
+
+use crate::link::LinkLoss;
+use crate::rate::{clamp_trim, GUIDE_RATE_PERCENT, MAX_GUIDE_RATE_PERCENT};
+use crate::startup::PowerOn;
+use crate::tmc::{Chopper, MAX_CURRENT_MA};
+
+/// The tracking waiting time of a unit that has never been configured.
+/// It is only a sane starting point, every platform has to be calibrated.
+pub const DEFAULT_WAITING_TIME: Microseconds = Microseconds(30_000);
+
+/// The finest microstep mode of the DRV8825.
+pub const MAX_MICROSTEPS: u8 = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Config {
+    /// The base waiting time between two steps while tracking.
+    pub waiting_time: Microseconds,
+    pub trim_ppm: i32,
+    /// The travel limit in steps.
+    pub travel_limit: Option<i32>,
+    pub park_position: i32,
+    pub power_on: PowerOn,
+    /// The microsteps per full step, a power of two.
+    pub microsteps: u8,
+    /// The motor is mounted the other way round.
+    pub reversed: bool,
+    pub guide_rate_percent: u8,
+    /// The seconds the host may be silent while slewing or guiding, zero disables the watch.
+    pub link_timeout_seconds: u16,
+    pub link_loss: LinkLoss,
+    /// The supply voltage in millivolts the user is warned at, `None` disables the warning.
+    pub supply_warning_mv: Option<u16>,
+    /// The supply voltage in millivolts the platform parks at, `None` disables the cutoff.
+    pub supply_cutoff_mv: Option<u16>,
+    /// The settings of a TMC driver, the currents in steps of 10 mA.
+    pub run_current_ma: u16,
+    pub hold_current_ma: u16,
+    pub chopper: Chopper,
+    /// The driver interpolates the microsteps to 256.
+    pub interpolation: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            waiting_time: DEFAULT_WAITING_TIME,
+            trim_ppm: 0,
+            travel_limit: None,
+            park_position: 0,
+            power_on: PowerOn::Track,
+            microsteps: MAX_MICROSTEPS,
+            reversed: false,
+            guide_rate_percent: GUIDE_RATE_PERCENT,
+            link_timeout_seconds: 0,
+            link_loss: LinkLoss::Track,
+            supply_warning_mv: None,
+            supply_cutoff_mv: None,
+            run_current_ma: 800,
+            hold_current_ma: 400,
+            chopper: Chopper::StealthChop,
+            interpolation: true,
+        }
+    }
+}
+
+impl Config {
+    /// Replaces values that can not be used by the defaults.
+    pub fn sanitized(self) -> Self {
+        let defaults = Self::default();
+
+        Self {
+            waiting_time: if *self.waiting_time.integer() == 0 {
+                defaults.waiting_time
+            } else {
+                self.waiting_time
+            },
+            trim_ppm: clamp_trim(self.trim_ppm),
+            travel_limit: self.travel_limit.filter(|limit| *limit >= 0),
+            park_position: self.park_position.max(0),
+            power_on: self.power_on,
+            microsteps: if self.microsteps.is_power_of_two() && self.microsteps <= MAX_MICROSTEPS {
+                self.microsteps
+            } else {
+                defaults.microsteps
+            },
+            reversed: self.reversed,
+            guide_rate_percent: if (1..=MAX_GUIDE_RATE_PERCENT).contains(&self.guide_rate_percent) {
+                self.guide_rate_percent
+            } else {
+                defaults.guide_rate_percent
+            },
+            link_timeout_seconds: self.link_timeout_seconds,
+            link_loss: self.link_loss,
+            supply_warning_mv: self.supply_warning_mv.filter(|millivolts| *millivolts != 0),
+            supply_cutoff_mv: self.supply_cutoff_mv.filter(|millivolts| *millivolts != 0),
+            run_current_ma: self.run_current_ma.min(MAX_CURRENT_MA),
+            hold_current_ma: self.hold_current_ma.min(MAX_CURRENT_MA),
+            chopper: self.chopper,
+            interpolation: self.interpolation,
+        }
+    }
+}
+
+pub const PROFILE_NAME_LENGTH: usize = 8;
+
+/// The name of a profile. Longer names are truncated, shorter ones padded with zeros.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProfileName(pub [u8; PROFILE_NAME_LENGTH]);
+
+impl ProfileName {
+    pub fn new(name: &str) -> Self {
+        let mut bytes = [0_u8; PROFILE_NAME_LENGTH];
+        for (byte, character) in bytes.iter_mut().zip(name.bytes()) {
+            *byte = character;
+        }
+        Self(bytes)
+    }
+
+    /// A name that is no valid UTF-8 anymore is shown as "?".
+    pub fn as_str(&self) -> &str {
+        let length = self
+            .0
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(PROFILE_NAME_LENGTH);
+        core::str::from_utf8(&self.0[..length]).unwrap_or("?")
+    }
+}
+
+/// Where the configuration in use came from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Origin {
+    /// A valid configuration block was found.
+    Stored,
+    /// The configuration was converted from an older layout.
+    Migrated,
+    /// No usable configuration was found.
+    Defaults,
+    /// The profile selected for booting was loaded.
+    Profile(u8),
+}
+
+impl Origin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Origin::Stored => "stored",
+            Origin::Migrated => "migrated",
+            Origin::Defaults => "defaults",
+            Origin::Profile(_) => "profile",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unusable_values_are_replaced() {
+        let config = Config {
+            waiting_time: Microseconds(0),
+            trim_ppm: i32::MAX,
+            travel_limit: Some(-1),
+            park_position: -5,
+            power_on: PowerOn::Hold,
+            microsteps: 12,
+            reversed: true,
+            guide_rate_percent: 100,
+            link_timeout_seconds: 30,
+            link_loss: LinkLoss::Hold,
+            supply_warning_mv: Some(0),
+            supply_cutoff_mv: Some(10_800),
+            run_current_ma: 2000,
+            hold_current_ma: 300,
+            chopper: Chopper::SpreadCycle,
+            interpolation: false,
+        }
+        .sanitized();
+
+        assert_eq!(config.waiting_time, DEFAULT_WAITING_TIME);
+        assert_eq!(config.trim_ppm, crate::rate::MAX_TRIM_PPM);
+        assert_eq!(config.travel_limit, None);
+        assert_eq!(config.park_position, 0);
+        assert_eq!(config.power_on, PowerOn::Hold);
+        assert_eq!(config.microsteps, MAX_MICROSTEPS);
+        assert!(config.reversed);
+        assert_eq!(config.guide_rate_percent, GUIDE_RATE_PERCENT);
+        assert_eq!(config.link_timeout_seconds, 30);
+        assert_eq!(config.supply_warning_mv, None);
+        assert_eq!(config.supply_cutoff_mv, Some(10_800));
+        assert_eq!(config.run_current_ma, MAX_CURRENT_MA);
+        assert_eq!(config.hold_current_ma, 300);
+    }
+}
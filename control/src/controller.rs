@@ -0,0 +1,1651 @@
+//! The controller ties the serial commands, the state machine and the EEPROM
+//! together. The firmware only has to set up the hardware and poll it in a loop.
+
+use embedded_time::duration::*;
+
+#[cfg(feature = "bootloader")]
+use crate::auth;
+#[cfg(feature = "backup")]
+use crate::backup::{self, Import};
+#[cfg(feature = "profiles")]
+use crate::config::ProfileName;
+use crate::config::{Config, Origin};
+use crate::eeprom::{self, PanicRecord, ParkRecord};
+use crate::hardware::*;
+use crate::history::{Cause, Change, Entry, History, Setting};
+use crate::link::{Heartbeat, LinkLoss};
+use crate::reset::{ResetCause, Resets, RESET_REGION};
+use crate::serial::{InputVariant, SerialHandler, Status};
+use crate::session::{remaining_seconds, Session};
+use crate::startup::{Countdown, PowerOn};
+use crate::state_machine::*;
+#[cfg(feature = "supply")]
+use crate::supply::{SupplyLevel, SupplyMonitor, Thresholds};
+#[cfg(feature = "tmc")]
+use crate::tmc::{self, Chopper};
+use crate::wear_log::{Counters, WearLog, COUNTERS_REGION};
+
+/// The usage time is stored in steps of this many minutes. At most one step
+/// of tracking time is lost when the power is cut.
+const USAGE_SAVE_MINUTES: u32 = 10;
+
+/// Tells the caller of `Controller::poll` what to do next.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Control {
+    Continue,
+    /// The chip has to be reset.
+    Reset,
+    /// The bootloader has to be started for a firmware update. The motor is released.
+    #[cfg(feature = "bootloader")]
+    Bootloader,
+}
+
+pub struct Controller<H: Hardware> {
+    motor: H::Motor,
+    storage: H::Storage,
+    serial_handler: SerialHandler<H::Serial>,
+    clock: H::Clock,
+    #[cfg(feature = "supply")]
+    supply: H::Supply,
+    #[cfg(feature = "supply")]
+    supply_monitor: SupplyMonitor,
+    eq_tracker: EQTracker,
+    /// The settings as they are stored in the EEPROM.
+    config: Config,
+    config_origin: Origin,
+    history: History,
+    session: Session,
+    wear_log: WearLog<Counters>,
+    counters: Counters,
+    reset_log: WearLog<Resets>,
+    resets: Resets,
+    last_panic: Option<PanicRecord>,
+    /// The tracking time that is not yet stored in the wear-levelled log.
+    unsaved_usage_ms: u32,
+    countdown: Countdown,
+    heartbeat: Heartbeat,
+    /// The position in the blob, while an import is running.
+    #[cfg(feature = "backup")]
+    import: Option<Import>,
+    /// The records written last. They are read back once the EEPROM is idle.
+    unverified_config: Option<Config>,
+    #[cfg(feature = "profiles")]
+    unverified_profile: Option<(u8, ProfileName, Config)>,
+    /// The last challenge given, the next one is derived from it.
+    /// The first one of a boot from the random seed.
+    #[cfg(feature = "bootloader")]
+    last_challenge: u64,
+    /// The challenge the next bootloader command has to answer.
+    #[cfg(feature = "bootloader")]
+    bootloader_challenge: Option<u64>,
+    /// The settings still have to be written to the TMC driver. That takes
+    /// tens of milliseconds, so it is left to the next poll and never done
+    /// before the firmware has set up its watchdog.
+    #[cfg(feature = "tmc")]
+    driver_outdated: bool,
+    /// A reset or the bootloader waits for the pending EEPROM writes.
+    pending_exit: Option<Control>,
+}
+
+impl<H: Hardware> Controller<H> {
+    /// Loads the settings from the storage and starts as configured for power-up.
+    /// The cause of the reset is read from the chip by the caller, so is a
+    /// random seed for the bootloader challenges.
+    pub fn new(
+        motor: H::Motor,
+        mut storage: H::Storage,
+        serial: H::Serial,
+        clock: H::Clock,
+        #[cfg(feature = "supply")] supply: H::Supply,
+        mut reset_cause: ResetCause,
+        #[cfg(feature = "bootloader")] seed: u64,
+    ) -> Self {
+        let (mut wear_log, counters) = WearLog::load(COUNTERS_REGION, &storage);
+        let mut counters = counters.unwrap_or_else(|| Counters {
+            startups: eeprom::read_legacy_startups(&storage),
+            usage_minutes: 0,
+        });
+        // Explicit overflow
+        counters.startups = counters.startups.wrapping_add(1);
+        wear_log.write(&counters, &mut storage);
+
+        let (mut reset_log, resets) = WearLog::load(RESET_REGION, &storage);
+        let mut resets: Resets = resets.unwrap_or_default();
+        // The panic handler resets the chip with the watchdog.
+        let last_panic = eeprom::read_panic(&storage).map(|(panic, pending)| {
+            if pending {
+                reset_cause = ResetCause::Panic;
+                eeprom::acknowledge_panic(&mut storage);
+            }
+            panic
+        });
+        reset_cause = resets.count(reset_cause);
+        reset_log.write(&resets, &mut storage);
+
+        let (config, config_origin) = eeprom::load_config(&mut storage);
+
+        // The profile selected for booting replaces the stored settings.
+        #[cfg(feature = "profiles")]
+        let (config, config_origin) = eeprom::read_boot_profile(&storage)
+            .and_then(|slot| {
+                eeprom::read_profile(slot, &storage)
+                    .map(|(_, profile)| (profile, Origin::Profile(slot)))
+            })
+            .unwrap_or((config, config_origin));
+
+        let eq_tracker = EQTracker::new(config.waiting_time, config.trim_ppm);
+
+        let mut controller = Self {
+            motor,
+            storage,
+            serial_handler: SerialHandler::new(serial),
+            session: Session::new(clock.millis()),
+            heartbeat: Heartbeat::new(clock.millis()),
+            #[cfg(feature = "supply")]
+            supply_monitor: SupplyMonitor::new(clock.millis()),
+            #[cfg(feature = "supply")]
+            supply,
+            wear_log,
+            counters,
+            reset_log,
+            resets,
+            last_panic,
+            unsaved_usage_ms: 0,
+            clock,
+            eq_tracker,
+            config,
+            config_origin,
+            history: History::new(),
+            countdown: Countdown::new(),
+            #[cfg(feature = "backup")]
+            import: None,
+            unverified_config: None,
+            #[cfg(feature = "profiles")]
+            unverified_profile: None,
+            #[cfg(feature = "bootloader")]
+            last_challenge: seed,
+            #[cfg(feature = "bootloader")]
+            bootloader_challenge: None,
+            #[cfg(feature = "tmc")]
+            driver_outdated: true,
+            pending_exit: None,
+        };
+        controller.apply_config();
+
+        // A parked platform stays parked regardless of the power-on behaviour.
+        if let Some(park) = eeprom::read_park(&controller.storage) {
+            controller.motor.set_position(park.position);
+            controller
+                .eq_tracker
+                .restore_park(park.resume, &mut controller.motor);
+            controller.history.record(Entry {
+                time: controller.clock.millis(),
+                change: Change::Transition {
+                    from: State::Hold,
+                    to: State::Parked,
+                    event: Event::Park,
+                    refused: false,
+                },
+                cause: Cause::Boot(reset_cause),
+            });
+            return controller;
+        }
+
+        let boot = Cause::Boot(reset_cause);
+        match controller.config.power_on {
+            PowerOn::Track => {
+                controller.dispatch(Event::Track, boot).ok();
+            }
+            PowerOn::Hold => {
+                controller.record(State::Hold, Event::Hold, boot, Ok(State::Hold));
+            }
+            PowerOn::Delayed(minutes) => {
+                controller.record(State::Hold, Event::Hold, boot, Ok(State::Hold));
+                let now = controller.clock.millis();
+                controller.countdown.arm(now, minutes);
+            }
+        }
+        controller
+    }
+
+    /// Handles the pending serial input and lets the state machine check for
+    /// reached targets, the travel limit and the end switches. Has to be
+    /// called in a loop.
+    pub fn poll(&mut self) -> Control {
+        let now = self.clock.millis();
+        self.unsaved_usage_ms += self.session.update(now, self.eq_tracker.is_tracking());
+        let steps = self.unsaved_usage_ms / (USAGE_SAVE_MINUTES * 60_000);
+        if steps > 0 {
+            self.unsaved_usage_ms -= steps * USAGE_SAVE_MINUTES * 60_000;
+            self.counters.usage_minutes += steps * USAGE_SAVE_MINUTES;
+            self.wear_log.write(&self.counters, &mut self.storage);
+        }
+
+        if !self.storage.is_busy() {
+            self.verify_writes();
+        }
+
+        #[cfg(feature = "tmc")]
+        if self.driver_outdated {
+            self.driver_outdated = false;
+            self.configure_driver();
+        }
+
+        if self.countdown.expired(now) {
+            self.serial_handler.write_str("Countdown expired, track!\n");
+            self.dispatch(Event::Track, Cause::Countdown).ok();
+        }
+
+        #[cfg(feature = "supply")]
+        self.check_supply(now);
+
+        let input = self.serial_handler.handle_input();
+
+        if input.is_some() {
+            self.heartbeat.seen(now);
+        } else if self
+            .heartbeat
+            .expired(now, self.config.link_timeout_seconds)
+            && matches!(
+                self.eq_tracker.get_state(),
+                State::Slewing(_) | State::Guiding(_)
+            )
+        {
+            self.link_lost();
+        }
+
+        let result = match input {
+            Some(InputVariant::Track) => {
+                self.serial_handler.write_str("Track!\n");
+                self.dispatch(Event::Track, Cause::Command)
+            }
+
+            // A refused command keeps the old time as well.
+            Some(InputVariant::TrackNewTime(duration)) => {
+                let result = self.dispatch(Event::Track, Cause::Command);
+                if result.is_ok() {
+                    self.eq_tracker.set_waiting_time(duration, &mut self.motor);
+                    let time = *self.eq_tracker.get_waiting_time().integer();
+                    self.record_setting(Setting::TrackingTime(time));
+                    self.serial_handler.write_str("Track with new duration: ");
+                    self.serial_handler.write_number(time);
+                    self.serial_handler.write_str("us\n");
+                }
+                result
+            }
+
+            Some(InputVariant::SetTrim(trim_ppm)) => {
+                // The base waiting time stays untouched, only the trimmed time changes.
+                self.eq_tracker.set_trim(trim_ppm, &mut self.motor);
+                self.record_setting(Setting::TrimPpm(self.eq_tracker.get_trim()));
+                self.report_trim();
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::AdjustTrim(delta_ppm)) => {
+                self.eq_tracker.adjust_trim(delta_ppm, &mut self.motor);
+                self.record_setting(Setting::TrimPpm(self.eq_tracker.get_trim()));
+                self.report_trim();
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::SetTravelLimit(limit)) => {
+                self.eq_tracker.set_travel_limit(limit);
+                self.config.travel_limit = limit;
+                self.save_config();
+                self.record_setting(Setting::TravelLimit(limit));
+                self.serial_handler.write_str("Travel limit: ");
+                match limit {
+                    Some(limit) => {
+                        self.serial_handler.write_number(limit);
+                        self.serial_handler.write_str(" steps\n");
+                    }
+                    None => self.serial_handler.write_str("none\n"),
+                }
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::SetParkPosition(position)) => {
+                self.eq_tracker.set_park_position(position);
+                self.config.park_position = position;
+                self.save_config();
+                self.record_setting(Setting::ParkPosition(position));
+                self.serial_handler.write_str("Park position: ");
+                self.serial_handler.write_number(position);
+                self.serial_handler.write_str(" steps\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::SetMicrosteps(microsteps)) => self.set_microsteps(microsteps),
+
+            Some(InputVariant::SetReversed(reversed)) => self.set_reversed(reversed),
+
+            #[cfg(feature = "guiding")]
+            Some(InputVariant::SetGuideRate(rate_percent)) => {
+                self.eq_tracker
+                    .set_guide_rate(rate_percent, &mut self.motor);
+                self.config.guide_rate_percent = rate_percent;
+                self.save_config();
+                self.record_setting(Setting::GuideRatePercent(rate_percent));
+                self.serial_handler.write_str("Guide rate: ");
+                self.serial_handler.write_number(rate_percent);
+                self.serial_handler.write_str("%\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::SetLinkTimeout(seconds)) => {
+                self.config.link_timeout_seconds = seconds;
+                self.save_config();
+                self.serial_handler.write_str("Link timeout: ");
+                self.serial_handler.write_number(seconds);
+                self.serial_handler.write_str("s\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::SetLinkLoss(link_loss)) => {
+                self.config.link_loss = link_loss;
+                self.save_config();
+                self.serial_handler.write_str("On link loss: ");
+                self.serial_handler.write_str(link_loss.name());
+                self.serial_handler.write_str("\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::Heartbeat) => Ok(self.eq_tracker.get_state()),
+
+            #[cfg(feature = "supply")]
+            Some(InputVariant::SetSupplyWarning(millivolts)) => {
+                self.config.supply_warning_mv = millivolts;
+                self.save_config();
+                self.serial_handler.write_str("Supply warning: ");
+                self.report_threshold(millivolts);
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "supply")]
+            Some(InputVariant::SetSupplyCutoff(millivolts)) => {
+                self.config.supply_cutoff_mv = millivolts;
+                self.save_config();
+                self.serial_handler.write_str("Supply cutoff: ");
+                self.report_threshold(millivolts);
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "tmc")]
+            Some(InputVariant::DriverStatus) => {
+                match tmc::read_status(&mut self.motor) {
+                    Some(status) => self.serial_handler.send_driver_status(&status),
+                    None => self.serial_handler.write_str("Driver not answering!\n"),
+                }
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "tmc")]
+            Some(InputVariant::SetRunCurrent(milliamps)) => {
+                self.config.run_current_ma = milliamps;
+                self.save_config();
+                self.configure_driver();
+                self.serial_handler.write_str("Run current: ");
+                self.serial_handler.write_number(milliamps);
+                self.serial_handler.write_str("mA\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "tmc")]
+            Some(InputVariant::SetHoldCurrent(milliamps)) => {
+                self.config.hold_current_ma = milliamps;
+                self.save_config();
+                self.configure_driver();
+                self.serial_handler.write_str("Hold current: ");
+                self.serial_handler.write_number(milliamps);
+                self.serial_handler.write_str("mA\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "tmc")]
+            Some(InputVariant::SetChopper(chopper)) => self.set_chopper(chopper),
+
+            #[cfg(feature = "tmc")]
+            Some(InputVariant::SetInterpolation(interpolation)) => {
+                self.set_interpolation(interpolation)
+            }
+
+            #[cfg(feature = "profiles")]
+            Some(InputVariant::ListProfiles) => {
+                self.list_profiles();
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "profiles")]
+            Some(InputVariant::SaveProfile(slot, name)) => {
+                let config = self.current_config();
+                eeprom::write_profile(slot, &name, &config, &mut self.storage);
+                self.unverified_profile = Some((slot, name, config));
+                self.serial_handler.write_str("Saved profile ");
+                self.serial_handler.write_number(slot);
+                self.serial_handler.write_str("!\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "profiles")]
+            Some(InputVariant::LoadProfile(slot)) => self.load_profile(slot),
+
+            #[cfg(feature = "profiles")]
+            Some(InputVariant::SetBootProfile(slot)) => {
+                match slot {
+                    Some(slot) if eeprom::read_profile(slot, &self.storage).is_none() => {
+                        self.serial_handler.write_str("Empty profile!\n");
+                    }
+                    _ => {
+                        eeprom::write_boot_profile(slot, &mut self.storage);
+                        self.serial_handler.write_str("Boot profile set!\n");
+                    }
+                }
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "backup")]
+            Some(InputVariant::Export) => {
+                let storage = &self.storage;
+                self.serial_handler
+                    .send_export(|write| backup::export(storage, write));
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "backup")]
+            Some(InputVariant::ImportBegin) => {
+                self.import = Some(Import::new());
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "backup")]
+            Some(InputVariant::ImportData(chunk)) => {
+                match self.import.as_mut() {
+                    Some(import) => import.append(chunk.as_bytes(), &mut self.storage),
+                    None => self.serial_handler.write_str("No import running!\n"),
+                }
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "backup")]
+            Some(InputVariant::ImportCommit) => self.import(),
+
+            Some(InputVariant::SetPowerOn(power_on)) => {
+                self.config.power_on = power_on;
+                self.save_config();
+                self.serial_handler.write_str("Power-on: ");
+                self.serial_handler.write_str(power_on.name());
+                if let PowerOn::Delayed(minutes) = power_on {
+                    self.serial_handler.write_str(" by ");
+                    self.serial_handler.write_number(minutes);
+                    self.serial_handler.write_str("min");
+                }
+                self.serial_handler.write_str("\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::ArmCountdown(minutes)) => {
+                self.countdown.arm(now, minutes);
+                self.serial_handler.write_str("Track in ");
+                self.serial_handler.write_number(minutes);
+                self.serial_handler.write_str("min\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::CancelCountdown) => {
+                self.countdown.cancel();
+                self.serial_handler.write_str("Countdown cancelled!\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::Hold) => {
+                self.serial_handler.write_str("Hold Hold Hold!\n");
+                self.dispatch(Event::Hold, Cause::Command)
+            }
+
+            Some(InputVariant::FastForward(direction)) => {
+                self.serial_handler.write_str("Fast Forward Mode!\n");
+                self.dispatch(Event::Slew(direction), Cause::Command)
+            }
+
+            #[cfg(feature = "guiding")]
+            Some(InputVariant::Guide(direction)) => {
+                self.dispatch(Event::Guide(direction), Cause::GuidePulse)
+            }
+
+            #[cfg(feature = "guiding")]
+            Some(InputVariant::GuideDone) => self.dispatch(Event::GuideDone, Cause::GuidePulse),
+
+            Some(InputVariant::Rewind) => {
+                self.serial_handler.write_str("Rewind!\n");
+                self.dispatch(Event::Rewind, Cause::Command)
+            }
+
+            Some(InputVariant::Home) => {
+                self.serial_handler.write_str("Home!\n");
+                self.dispatch(Event::Home, Cause::Command)
+            }
+
+            Some(InputVariant::Park) => {
+                self.serial_handler.write_str("Park!\n");
+                self.dispatch(Event::Park, Cause::Command)
+            }
+
+            Some(InputVariant::Unpark) => {
+                self.serial_handler.write_str("Unpark!\n");
+                self.dispatch(Event::Unpark, Cause::Command)
+            }
+
+            Some(InputVariant::ClearFault) => {
+                self.serial_handler.write_str("Clear fault!\n");
+                self.dispatch(Event::ClearFault, Cause::Command)
+            }
+
+            Some(InputVariant::SetDefault) => {
+                self.serial_handler.write_str("Write Default Value!\n");
+                self.config = self.current_config();
+                self.save_config();
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::Status) => {
+                let status = self.status();
+                self.serial_handler.send_status(&status);
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::BinaryStatus) => {
+                let status = self.status();
+                self.serial_handler.send_binary_status(&status);
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::History) => {
+                self.serial_handler.send_history(&self.history);
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::Invalid) => {
+                self.serial_handler.write_str("Invalid operation!\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            Some(InputVariant::Reset) => {
+                self.serial_handler.write_str("Reset!\n");
+                self.request_exit(Control::Reset);
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "bootloader")]
+            Some(InputVariant::BootloaderChallenge) => {
+                // Never the same twice, so an old answer can not be replayed.
+                let challenge = auth::next_challenge(
+                    &H::BOOTLOADER_KEY,
+                    self.last_challenge,
+                    self.counters.startups,
+                );
+                self.last_challenge = challenge;
+
+                self.bootloader_challenge = Some(challenge);
+                self.serial_handler.write_str("Bootloader challenge: ");
+                self.serial_handler.write_hex(challenge);
+                self.serial_handler.write_str("\n");
+                Ok(self.eq_tracker.get_state())
+            }
+
+            #[cfg(feature = "bootloader")]
+            Some(InputVariant::Bootloader(response)) => self.enter_bootloader(response),
+
+            None => Ok(self.eq_tracker.get_state()),
+        };
+
+        match result {
+            Ok(_) => (),
+            Err(TransitionError::Parked) => self.serial_handler.write_str("Refused: parked!\n"),
+            Err(TransitionError::Faulted) => self.serial_handler.write_str("Refused: fault!\n"),
+            Err(TransitionError::NotAllowed) => self.serial_handler.write_str("Refused!\n"),
+        }
+
+        // A closed end switch stops every motion towards it, so a target
+        // behind it is never reached.
+        if self.motor.take_limit_hit() && self.eq_tracker.get_state() != State::Fault {
+            self.serial_handler.write_str("End switch closed!\n");
+            self.dispatch(Event::Fault, Cause::Limit).ok();
+        }
+
+        // Let the state machine check for reached targets and the travel limit.
+        let position = self.motor.position();
+        let from = self.eq_tracker.get_state();
+        if let Some(event) = self.eq_tracker.update(position, &mut self.motor) {
+            let cause = match event {
+                Event::Fault => Cause::Limit,
+                _ => Cause::Target,
+            };
+            // Back at the start of the travel, so a new session begins.
+            if matches!(from, State::Rewinding | State::Homing) && event == Event::Arrived {
+                self.session.restart(self.clock.millis());
+            }
+            let to = self.eq_tracker.get_state();
+            self.record(from, event, cause, Ok(to));
+        }
+
+        match self.pending_exit {
+            Some(exit) if !self.storage.is_busy() => exit,
+            _ => Control::Continue,
+        }
+    }
+
+    /// Leaves once the EEPROM is idle. The next boot is told that it was asked for.
+    fn request_exit(&mut self, exit: Control) {
+        self.resets.requested = true;
+        self.reset_log.write(&self.resets, &mut self.storage);
+        self.pending_exit = Some(exit);
+    }
+
+    /// Every challenge can be answered once, a wrong answer needs a new one.
+    #[cfg(feature = "bootloader")]
+    fn enter_bootloader(&mut self, response: u64) -> Result<State, TransitionError> {
+        let accepted = match self.bootloader_challenge.take() {
+            Some(challenge) => response == auth::response(&H::BOOTLOADER_KEY, challenge),
+            None => false,
+        };
+        if !accepted {
+            self.serial_handler.write_str("Refused: wrong key!\n");
+            return Ok(self.eq_tracker.get_state());
+        }
+
+        // Stop the motor before the chip resets, the driver is released for the update.
+        let state = match self.eq_tracker.get_state() {
+            State::Hold | State::Parked => Ok(self.eq_tracker.get_state()),
+            _ => self.dispatch(Event::Hold, Cause::Command),
+        };
+        self.motor.set_enabled(false);
+
+        self.serial_handler.write_str("Bootloader!\n");
+        self.request_exit(Control::Bootloader);
+        state
+    }
+
+    /// Ends the slew or the guide pulse nobody is going to end anymore.
+    fn link_lost(&mut self) {
+        let event = match self.config.link_loss {
+            LinkLoss::Track => {
+                self.serial_handler.write_str("Host lost, track!\n");
+                Event::Track
+            }
+            LinkLoss::Hold => {
+                self.serial_handler.write_str("Host lost, hold!\n");
+                Event::Hold
+            }
+        };
+        self.dispatch(event, Cause::LinkLoss).ok();
+    }
+
+    #[cfg(feature = "supply")]
+    fn check_supply(&mut self, now: u32) {
+        if !self.supply_monitor.is_due(now) {
+            return;
+        }
+        if let Some(millivolts) = self.supply.millivolts() {
+            let thresholds = Thresholds {
+                warning: self.config.supply_warning_mv,
+                cutoff: self.config.supply_cutoff_mv,
+            };
+            if let Some(level) = self.supply_monitor.update(now, millivolts, thresholds) {
+                self.supply_changed(level);
+            }
+        }
+    }
+
+    /// Slews slower while the supply is low and parks while the motor can still move.
+    #[cfg(feature = "supply")]
+    fn supply_changed(&mut self, level: SupplyLevel) {
+        self.eq_tracker
+            .set_reduced_slew(level != SupplyLevel::Normal, &mut self.motor);
+
+        self.serial_handler.write_str(match level {
+            SupplyLevel::Normal => "Battery ok: ",
+            SupplyLevel::Low => "Low battery: ",
+            SupplyLevel::Critical => "Battery critical: ",
+        });
+        self.serial_handler
+            .write_number(self.supply_monitor.millivolts().unwrap_or(0));
+        self.serial_handler.write_str(match level {
+            SupplyLevel::Normal => "mV\n",
+            SupplyLevel::Low => "mV, slewing slower!\n",
+            SupplyLevel::Critical => "mV, park!\n",
+        });
+
+        let parking = matches!(
+            self.eq_tracker.get_state(),
+            State::Parking(_) | State::Parked | State::Fault
+        );
+        if level == SupplyLevel::Critical && !parking {
+            self.dispatch(Event::Park, Cause::Supply).ok();
+        }
+    }
+
+    /// Feeds an event into the state machine and keeps track of it in the history.
+    /// A motion command of the user cancels a pending countdown.
+    fn dispatch(&mut self, event: Event, cause: Cause) -> Result<State, TransitionError> {
+        if cause == Cause::Command {
+            self.countdown.cancel();
+        }
+
+        let from = self.eq_tracker.get_state();
+        let result = self.eq_tracker.handle(event, &mut self.motor);
+        self.record(from, event, cause, result);
+        result
+    }
+
+    /// Keeps track of a handled event in the history and
+    /// stores the parked state, so it survives a power cycle.
+    fn record(
+        &mut self,
+        from: State,
+        event: Event,
+        cause: Cause,
+        result: Result<State, TransitionError>,
+    ) {
+        let to = result.unwrap_or(from);
+
+        if to == State::Parked && from != State::Parked {
+            let park = ParkRecord {
+                position: self.motor.position(),
+                resume: self.eq_tracker.get_resume(),
+            };
+            eeprom::write_park(Some(park), &mut self.storage);
+        } else if from == State::Parked && to != State::Parked {
+            eeprom::write_park(None, &mut self.storage);
+        }
+
+        self.history.record(Entry {
+            time: self.clock.millis(),
+            change: Change::Transition {
+                from,
+                to,
+                event,
+                refused: result.is_err(),
+            },
+            cause,
+        });
+    }
+
+    /// Keeps track of a setting changed by a command in the history.
+    fn record_setting(&mut self, setting: Setting) {
+        self.history.record(Entry {
+            time: self.clock.millis(),
+            change: Change::Setting(setting),
+            cause: Cause::Command,
+        });
+    }
+
+    /// The stored settings with the tracking time and the trim currently in use.
+    fn current_config(&self) -> Config {
+        Config {
+            waiting_time: self.eq_tracker.get_waiting_time(),
+            trim_ppm: self.eq_tracker.get_trim(),
+            ..self.config
+        }
+    }
+
+    fn save_config(&mut self) {
+        eeprom::write_config(&self.config, &mut self.storage);
+        self.unverified_config = Some(self.config);
+    }
+
+    /// Reads the records written last back. If they did not make it, the EEPROM
+    /// still holds the previous values, but the user has to know about it.
+    fn verify_writes(&mut self) {
+        let mut failed = false;
+        if let Some(config) = self.unverified_config.take() {
+            failed |= eeprom::read_config(&self.storage) != Some(config);
+        }
+        #[cfg(feature = "profiles")]
+        if let Some((slot, name, config)) = self.unverified_profile.take() {
+            failed |= eeprom::read_profile(slot, &self.storage) != Some((name, config));
+        }
+
+        if failed {
+            self.serial_handler.write_str("EEPROM write failed!\n");
+        }
+    }
+
+    /// Hands the settings over to the state machine and the motor driver.
+    fn apply_config(&mut self) {
+        let config = self.config;
+
+        self.motor.set_microsteps(config.microsteps);
+        self.motor.set_reversed(config.reversed);
+        self.eq_tracker
+            .set_waiting_time(config.waiting_time, &mut self.motor);
+        self.eq_tracker.set_trim(config.trim_ppm, &mut self.motor);
+        self.eq_tracker
+            .set_guide_rate(config.guide_rate_percent, &mut self.motor);
+        self.eq_tracker.set_travel_limit(config.travel_limit);
+        self.eq_tracker.set_park_position(config.park_position);
+        // Written on the next poll, see `driver_outdated`.
+        #[cfg(feature = "tmc")]
+        {
+            self.driver_outdated = true;
+        }
+    }
+
+    /// Writes the settings to the TMC driver. A missing driver is only reported,
+    /// the motor may well be driven by one without a UART.
+    #[cfg(feature = "tmc")]
+    fn configure_driver(&mut self) {
+        if !tmc::configure(&mut self.motor, &self.config) {
+            self.serial_handler.write_str("Driver not answering!\n");
+        }
+    }
+
+    /// Changing the driver settings while the platform moves would mess up its position.
+    fn require_hold(&self) -> Result<State, TransitionError> {
+        match self.eq_tracker.get_state() {
+            State::Hold => Ok(State::Hold),
+            State::Parked => Err(TransitionError::Parked),
+            State::Fault => Err(TransitionError::Faulted),
+            _ => Err(TransitionError::NotAllowed),
+        }
+    }
+
+    fn set_microsteps(&mut self, microsteps: u8) -> Result<State, TransitionError> {
+        let state = self.require_hold()?;
+
+        self.motor.set_microsteps(microsteps);
+        self.config.microsteps = microsteps;
+        self.save_config();
+        #[cfg(feature = "tmc")]
+        self.configure_driver();
+
+        self.serial_handler.write_str("Microsteps: 1/");
+        self.serial_handler.write_number(microsteps);
+        self.serial_handler.write_str("\n");
+        Ok(state)
+    }
+
+    fn set_reversed(&mut self, reversed: bool) -> Result<State, TransitionError> {
+        let state = self.require_hold()?;
+
+        self.motor.set_reversed(reversed);
+        self.config.reversed = reversed;
+        self.save_config();
+
+        self.serial_handler.write_str(if reversed {
+            "Direction reversed!\n"
+        } else {
+            "Direction normal!\n"
+        });
+        Ok(state)
+    }
+
+    /// The chopper is not switched while the motor turns.
+    #[cfg(feature = "tmc")]
+    fn set_chopper(&mut self, chopper: Chopper) -> Result<State, TransitionError> {
+        let state = self.require_hold()?;
+
+        self.config.chopper = chopper;
+        self.save_config();
+        self.configure_driver();
+
+        self.serial_handler.write_str("Chopper: ");
+        self.serial_handler.write_str(chopper.name());
+        self.serial_handler.write_str("\n");
+        Ok(state)
+    }
+
+    #[cfg(feature = "tmc")]
+    fn set_interpolation(&mut self, interpolation: bool) -> Result<State, TransitionError> {
+        let state = self.require_hold()?;
+
+        self.config.interpolation = interpolation;
+        self.save_config();
+        self.configure_driver();
+
+        self.serial_handler.write_str(if interpolation {
+            "Interpolation on!\n"
+        } else {
+            "Interpolation off!\n"
+        });
+        Ok(state)
+    }
+
+    /// Loads the profile and makes it the stored settings.
+    #[cfg(feature = "profiles")]
+    fn load_profile(&mut self, slot: u8) -> Result<State, TransitionError> {
+        let state = self.require_hold()?;
+
+        match eeprom::read_profile(slot, &self.storage) {
+            Some((name, config)) => {
+                self.config = config;
+                self.save_config();
+                self.apply_config();
+                self.record_setting(Setting::Profile(slot));
+
+                self.serial_handler.write_str("Loaded profile ");
+                self.serial_handler.write_str(name.as_str());
+                self.serial_handler.write_str("!\n");
+            }
+            None => self.serial_handler.write_str("Empty profile!\n"),
+        }
+        Ok(state)
+    }
+
+    /// Writes the received blob. The settings change at once, so the platform has to hold.
+    #[cfg(feature = "backup")]
+    fn import(&mut self) -> Result<State, TransitionError> {
+        let state = self.require_hold()?;
+
+        let import = match self.import.take() {
+            Some(import) => import,
+            None => {
+                self.serial_handler.write_str("No import running!\n");
+                return Ok(state);
+            }
+        };
+
+        match import.commit(&mut self.storage) {
+            Ok(config) => {
+                self.config = config.unwrap_or_default();
+                self.unverified_config = config;
+                self.config_origin = match config {
+                    Some(_) => Origin::Stored,
+                    None => Origin::Defaults,
+                };
+                self.apply_config();
+                self.record_setting(Setting::Import);
+                self.serial_handler.write_str("Imported!\n");
+            }
+            Err(error) => self.report_import_error(error.name()),
+        }
+        Ok(state)
+    }
+
+    #[cfg(feature = "backup")]
+    fn report_import_error(&mut self, reason: &str) {
+        self.serial_handler.write_str("Import failed: ");
+        self.serial_handler.write_str(reason);
+        self.serial_handler.write_str("!\n");
+    }
+
+    #[cfg(feature = "profiles")]
+    fn list_profiles(&mut self) {
+        let boot = eeprom::read_boot_profile(&self.storage);
+
+        for slot in 0..eeprom::PROFILE_SLOTS {
+            self.serial_handler.write_str("Profile ");
+            self.serial_handler.write_number(slot);
+            self.serial_handler.write_str(": ");
+            match eeprom::read_profile(slot, &self.storage) {
+                Some((name, _)) => self.serial_handler.write_str(name.as_str()),
+                None => self.serial_handler.write_str("-"),
+            }
+            if boot == Some(slot) {
+                self.serial_handler.write_str(" (boot)");
+            }
+            self.serial_handler.write_str("\n");
+        }
+    }
+
+    #[cfg(feature = "supply")]
+    fn report_threshold(&mut self, millivolts: Option<u16>) {
+        match millivolts {
+            Some(millivolts) => {
+                self.serial_handler.write_number(millivolts);
+                self.serial_handler.write_str("mV\n");
+            }
+            None => self.serial_handler.write_str("off\n"),
+        }
+    }
+
+    fn report_trim(&mut self) {
+        self.serial_handler.write_str("Rate trim: ");
+        self.serial_handler.write_number(self.eq_tracker.get_trim());
+        self.serial_handler.write_str("ppm\n");
+    }
+
+    fn status(&self) -> Status<'static> {
+        Status {
+            state: self.eq_tracker.get_state().name(),
+            current_time: *self.eq_tracker.get_waiting_time().integer(),
+            default_time: *self.config.waiting_time.integer(),
+            trim: self.eq_tracker.get_trim(),
+            position: self.motor.position(),
+            starts: self.counters.startups,
+            usage_minutes: self.counters.usage_minutes + self.unsaved_usage_ms / 60_000,
+            uptime_seconds: self.clock.millis() / 1000,
+            session_seconds: self.session.tracked_seconds(),
+            travel_limit: self.eq_tracker.get_travel_limit(),
+            park_position: self.eq_tracker.get_park_position(),
+            remaining_seconds: self.eq_tracker.get_travel_limit().map(|limit| {
+                remaining_seconds(
+                    self.motor.position(),
+                    limit,
+                    self.eq_tracker.get_tracking_time(),
+                )
+            }),
+            power_on: self.config.power_on.name(),
+            start_in_seconds: self.countdown.remaining_seconds(self.clock.millis()),
+            config: self.config_origin.name(),
+            link_timeout_seconds: Some(self.config.link_timeout_seconds)
+                .filter(|seconds| *seconds != 0),
+            link_loss: self.config.link_loss.name(),
+            #[cfg(feature = "supply")]
+            supply_millivolts: self.supply_monitor.millivolts(),
+            #[cfg(feature = "supply")]
+            supply_level: self.supply_monitor.level().name(),
+            #[cfg(feature = "supply")]
+            supply_warning_mv: self.config.supply_warning_mv,
+            #[cfg(feature = "supply")]
+            supply_cutoff_mv: self.config.supply_cutoff_mv,
+            #[cfg(feature = "tmc")]
+            run_current_ma: self.config.run_current_ma,
+            #[cfg(feature = "tmc")]
+            hold_current_ma: self.config.hold_current_ma,
+            #[cfg(feature = "tmc")]
+            chopper: self.config.chopper.name(),
+            last_reset: self.resets.last.map_or("-", |cause| cause.name()),
+            resets: self.resets,
+            rx_errors: self.serial_handler.rx_errors(),
+            last_panic: self.last_panic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(all(feature = "backup", feature = "profiles"))]
+    use crate::config::ProfileName;
+    use crate::config::DEFAULT_WAITING_TIME;
+    use crate::mock::*;
+
+    fn controller() -> Controller<MockHardware> {
+        let mut storage = MockStorage::default();
+        let config = Config {
+            waiting_time: Microseconds(30_000),
+            ..Config::default()
+        };
+        eeprom::write_config(&config, &mut storage);
+
+        boot(storage, ResetCause::PowerOn)
+    }
+
+    /// Boots a controller on the storage, like the firmware after a reset.
+    fn boot(storage: MockStorage, reset_cause: ResetCause) -> Controller<MockHardware> {
+        #[cfg(feature = "supply")]
+        let controller = Controller::new(
+            MockMotor::default(),
+            storage,
+            MockSerial::default(),
+            MockClock::default(),
+            MockSupply::default(),
+            reset_cause,
+            #[cfg(feature = "bootloader")]
+            0x1234_5678,
+        );
+        #[cfg(not(feature = "supply"))]
+        let controller = Controller::new(
+            MockMotor::default(),
+            storage,
+            MockSerial::default(),
+            MockClock::default(),
+            reset_cause,
+            #[cfg(feature = "bootloader")]
+            0x1234_5678,
+        );
+        controller
+    }
+
+    fn send(controller: &mut Controller<MockHardware>, input: &str) -> Control {
+        controller.serial_handler.port().receive(input);
+        controller.poll()
+    }
+
+    #[test]
+    fn starts_tracking_with_the_stored_time() {
+        let controller = controller();
+
+        assert!(controller.motor.stepping);
+        assert_eq!(controller.motor.step_time, Microseconds(30_000_u32));
+    }
+
+    #[test]
+    fn trim_keeps_the_base_time() {
+        let mut controller = controller();
+        send(&mut controller, "p+1000\n");
+        send(&mut controller, "d\n");
+
+        assert_eq!(controller.motor.step_time, Microseconds(29_970_u32));
+
+        let (config, _) = eeprom::load_config(&mut controller.storage);
+        assert_eq!(config.waiting_time, Microseconds(30_000_u32));
+        assert_eq!(config.trim_ppm, 1000);
+    }
+
+    #[test]
+    fn motion_is_refused_while_parked() {
+        let mut controller = controller();
+        send(&mut controller, "k\n");
+        send(&mut controller, "+\n");
+
+        assert!(!controller.motor.stepping);
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Refused: parked!"));
+    }
+
+    #[test]
+    fn transitions_are_recorded_in_the_history() {
+        let mut controller = controller();
+        controller.clock.millis = 1500;
+        send(&mut controller, "k\n");
+        send(&mut controller, "+\n");
+        send(&mut controller, "l\n");
+
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("0 Hold -> Tracking (track, boot after power-on)\n"));
+        assert!(output.contains("1500 Tracking -> Parking (park, command)\n"));
+        assert!(output.contains("1500 Parking -> Parked (arrived, target)\n"));
+        assert!(output.contains("1500 Parked -> Parked (slew, command) refused\n"));
+    }
+
+    #[test]
+    fn refused_new_time_keeps_the_old_one() {
+        let mut controller = controller();
+        send(&mut controller, "k\n");
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Parked);
+
+        send(&mut controller, "25000\n");
+        assert_eq!(controller.eq_tracker.get_state(), State::Parked);
+        assert_eq!(
+            controller.eq_tracker.get_waiting_time(),
+            Microseconds(30_000_u32)
+        );
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Refused: parked!"));
+
+        send(&mut controller, "u\n");
+        send(&mut controller, "25000\n");
+        assert_eq!(
+            controller.eq_tracker.get_waiting_time(),
+            Microseconds(25_000_u32)
+        );
+        assert_eq!(controller.motor.step_time, Microseconds(25_000_u32));
+    }
+
+    #[test]
+    fn settings_commands_are_recorded_in_the_history() {
+        let mut controller = controller();
+        controller.clock.millis = 2000;
+        send(&mut controller, "p=-30\n");
+        send(&mut controller, "k=100\n");
+        send(&mut controller, "25000\n");
+        send(&mut controller, "l\n");
+
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("2000 trim = -30ppm (command)\n"));
+        assert!(output.contains("2000 park position = 100 steps (command)\n"));
+        assert!(output.contains("2000 tracking time = 25000us (command)\n"));
+    }
+
+    #[test]
+    fn status_shows_the_session_and_the_remaining_time() {
+        let mut controller = controller();
+        send(&mut controller, "m=11000\n");
+        controller.motor.position = 1000;
+        controller.clock.millis = 30_000;
+        send(&mut controller, "s\n");
+
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("Session (s): 30 "));
+        assert!(output.contains("Remaining (s): 300 "));
+        let (config, _) = eeprom::load_config(&mut controller.storage);
+        assert_eq!(config.travel_limit, Some(11_000));
+    }
+
+    #[test]
+    fn parking_keeps_the_session() {
+        let mut controller = controller();
+        controller.clock.millis = 30_000;
+        send(&mut controller, "k=100\n");
+        controller.motor.position = 300;
+        send(&mut controller, "k\n");
+        controller.motor.position = 100;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Parked);
+
+        send(&mut controller, "u\n");
+        controller.clock.millis = 40_000;
+        send(&mut controller, "s\n");
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Session (s): 40 "));
+
+        send(&mut controller, "w\n");
+        controller.motor.position = 0;
+        controller.poll();
+        send(&mut controller, "s\n");
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Session (s): 0 "));
+    }
+
+    #[test]
+    fn parked_state_survives_a_power_cycle() {
+        let mut controller = controller();
+        send(&mut controller, "k=100\n");
+        controller.motor.position = 300;
+        send(&mut controller, "k\n");
+        assert_eq!(controller.motor.direction, Direction::Backward);
+
+        controller.motor.position = 100;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Parked);
+        assert!(!controller.motor.enabled);
+
+        let mut controller = boot(controller.storage, ResetCause::PowerOn);
+        assert_eq!(controller.eq_tracker.get_state(), State::Parked);
+        assert_eq!(controller.motor.position, 100);
+        assert!(!controller.motor.stepping);
+
+        send(&mut controller, "u\n");
+        assert_eq!(controller.eq_tracker.get_state(), State::Tracking);
+        assert!(controller.motor.enabled);
+        assert_eq!(eeprom::read_park(&controller.storage), None);
+    }
+
+    #[test]
+    fn delayed_power_on_starts_tracking_later() {
+        let mut storage = MockStorage::default();
+        let config = Config {
+            power_on: PowerOn::Delayed(1),
+            ..Config::default()
+        };
+        eeprom::write_config(&config, &mut storage);
+
+        let mut controller = boot(storage, ResetCause::PowerOn);
+        assert_eq!(controller.eq_tracker.get_state(), State::Hold);
+
+        controller.clock.millis = 59_999;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Hold);
+
+        controller.clock.millis = 60_000;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Tracking);
+    }
+
+    #[test]
+    fn motion_commands_cancel_the_countdown() {
+        let mut controller = controller();
+        send(&mut controller, "h\n");
+        send(&mut controller, "a=1\n");
+        send(&mut controller, "+\n");
+        send(&mut controller, "h\n");
+
+        controller.clock.millis = 60_000;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Hold);
+    }
+
+    #[test]
+    fn fresh_eeprom_tracks_with_the_defaults() {
+        let controller = boot(MockStorage::default(), ResetCause::PowerOn);
+
+        assert_eq!(controller.config_origin, Origin::Defaults);
+        assert_eq!(controller.motor.step_time, DEFAULT_WAITING_TIME);
+    }
+
+    #[test]
+    fn counters_are_kept_in_the_wear_log() {
+        let mut controller = controller();
+        assert_eq!(controller.counters.startups, 1);
+
+        controller.clock.millis = 25 * 60_000;
+        controller.poll();
+
+        let mut controller = boot(controller.storage, ResetCause::PowerOn);
+        assert_eq!(
+            controller.counters,
+            Counters {
+                startups: 2,
+                usage_minutes: 20,
+            }
+        );
+
+        send(&mut controller, "s\n");
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Number of starts: 2 "));
+    }
+
+    #[test]
+    #[cfg(feature = "profiles")]
+    fn profiles_are_saved_and_loaded() {
+        let mut controller = controller();
+        send(&mut controller, "fs 0 eq40\n");
+
+        send(&mut controller, "h\n");
+        send(&mut controller, "x=8\n");
+        send(&mut controller, "v=1\n");
+        send(&mut controller, "25000\n");
+        send(&mut controller, "fs 1 barndoor\n");
+        send(&mut controller, "fb 1\n");
+        send(&mut controller, "h\n");
+        send(&mut controller, "fl 0\n");
+        assert_eq!(controller.motor.microsteps, 32);
+        assert!(!controller.motor.reversed);
+
+        send(&mut controller, "f\n");
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Profile 0: eq40\nProfile 1: barndoor (boot)\nProfile 2: -\n"));
+
+        let controller = boot(controller.storage, ResetCause::PowerOn);
+        assert_eq!(controller.config_origin, Origin::Profile(1));
+        assert_eq!(controller.motor.microsteps, 8);
+        assert!(controller.motor.reversed);
+        assert_eq!(controller.motor.step_time, Microseconds(25_000_u32));
+    }
+
+    #[test]
+    #[cfg(all(feature = "backup", feature = "profiles"))]
+    fn settings_are_exported_and_imported() {
+        let mut controller = controller();
+        send(&mut controller, "p=250\n");
+        send(&mut controller, "d\n");
+        send(&mut controller, "fs 3 eq40\n");
+        send(&mut controller, "e\n");
+        let output = controller.serial_handler.port().output.clone();
+
+        let mut other = boot(MockStorage::default(), ResetCause::PowerOn);
+        send(&mut other, "h\n");
+        for line in output.lines().filter(|line| line.starts_with('i')) {
+            send(&mut other, &format!("{}\n", line));
+        }
+
+        assert!(other.serial_handler.port().output.contains("Imported!"));
+        assert_eq!(other.eq_tracker.get_trim(), 250);
+        assert_eq!(other.config_origin, Origin::Stored);
+        assert_eq!(
+            eeprom::read_profile(3, &other.storage).map(|(name, _)| name),
+            Some(ProfileName::new("eq40"))
+        );
+
+        send(&mut other, "i+\n");
+        send(&mut other, "i:4558\n");
+        send(&mut other, "i=\n");
+        assert!(other
+            .serial_handler
+            .port()
+            .output
+            .contains("Import failed: corrupted!"));
+    }
+
+    #[test]
+    fn failed_writes_are_reported() {
+        let mut controller = controller();
+        send(&mut controller, "m=1000\n");
+        controller.poll();
+        assert!(!controller
+            .serial_handler
+            .port()
+            .output
+            .contains("EEPROM write failed!"));
+
+        controller.storage.fail_after(10);
+        send(&mut controller, "m=2000\n");
+        controller.poll();
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("EEPROM write failed!"));
+        assert_eq!(
+            eeprom::read_config(&controller.storage).and_then(|config| config.travel_limit),
+            Some(1000)
+        );
+    }
+
+    #[test]
+    fn driver_settings_need_hold() {
+        let mut controller = controller();
+        send(&mut controller, "x=4\n");
+
+        assert_eq!(controller.motor.microsteps, 32);
+        assert!(controller.serial_handler.port().output.contains("Refused!"));
+    }
+
+    #[test]
+    fn reset_is_passed_to_the_caller() {
+        let mut controller = controller();
+
+        assert!(matches!(send(&mut controller, "r\n"), Control::Reset));
+    }
+
+    #[test]
+    #[cfg(feature = "guiding")]
+    fn slew_ends_when_the_host_is_lost() {
+        let mut controller = controller();
+        send(&mut controller, "q=5\n");
+        send(&mut controller, "+\n");
+
+        controller.clock.millis = 4000;
+        send(&mut controller, "q\n");
+        controller.clock.millis = 8999;
+        controller.poll();
+        assert_eq!(
+            controller.eq_tracker.get_state(),
+            State::Slewing(Direction::Forward)
+        );
+
+        controller.clock.millis = 9000;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Tracking);
+
+        send(&mut controller, "q=h\n");
+        send(&mut controller, "g+\n");
+        controller.clock.millis = 20_000;
+        controller.poll();
+        assert_eq!(controller.eq_tracker.get_state(), State::Hold);
+
+        send(&mut controller, "l\n");
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("Host lost, hold!\n"));
+        assert!(output.contains("9000 Slewing -> Tracking (track, link lost)\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "supply")]
+    fn low_supply_slows_down_and_parks() {
+        let mut controller = controller();
+        send(&mut controller, "y=11500\n");
+        send(&mut controller, "z=10800\n");
+        send(&mut controller, "k=100\n");
+        controller.motor.position = 500;
+
+        let sample = |controller: &mut Controller<MockHardware>, millivolts| {
+            controller.supply.millivolts = Some(millivolts);
+            for _ in 0..20 {
+                controller.clock.millis += 100;
+                controller.poll();
+            }
+        };
+
+        sample(&mut controller, 11_200);
+        send(&mut controller, "-\n");
+        assert_eq!(controller.motor.step_time, Microseconds(2400_u32));
+
+        sample(&mut controller, 10_500);
+        assert_eq!(
+            controller.eq_tracker.get_state(),
+            State::Parking(Direction::Backward)
+        );
+
+        send(&mut controller, "l\n");
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("Low battery: "));
+        assert!(output.contains("Battery critical: "));
+        assert!(output.contains("Slewing -> Parking (park, low battery)\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "bootloader")]
+    fn bootloader_needs_the_key() {
+        let mut controller = controller();
+        let challenge = |controller: &mut Controller<MockHardware>| {
+            send(controller, "bootloader\n");
+            let output = &controller.serial_handler.port().output;
+            let line = output.lines().rev().find(|line| line.contains("challenge"));
+            let hex = line.unwrap().rsplit(' ').next().unwrap();
+            u64::from_str_radix(hex, 16).unwrap()
+        };
+
+        let wrong = auth::response(b"0123456789guess!", challenge(&mut controller));
+        let input = format!("bootloader={:016X}\n", wrong);
+        assert_eq!(send(&mut controller, &input), Control::Continue);
+        assert!(controller.motor.stepping);
+
+        let first = challenge(&mut controller);
+        let second = challenge(&mut controller);
+        assert_ne!(first, second);
+        // Only the last challenge counts.
+        let input = format!(
+            "bootloader={:016X}\n",
+            auth::response(&MockHardware::BOOTLOADER_KEY, first)
+        );
+        assert_eq!(send(&mut controller, &input), Control::Continue);
+
+        let right = auth::response(&MockHardware::BOOTLOADER_KEY, challenge(&mut controller));
+        let input = format!("bootloader={:016X}\n", right);
+        assert_eq!(send(&mut controller, &input), Control::Bootloader);
+        assert!(!controller.motor.stepping);
+        assert!(!controller.motor.enabled);
+        assert_eq!(controller.eq_tracker.get_state(), State::Hold);
+
+        // A challenge can not be answered twice.
+        controller.pending_exit = None;
+        assert_eq!(send(&mut controller, &input), Control::Continue);
+    }
+
+    #[test]
+    #[cfg(feature = "tmc")]
+    fn driver_is_configured_over_the_uart() {
+        let mut controller = controller();
+        // Not before the first poll.
+        assert_eq!(controller.motor.driver.ifcnt, 0);
+        controller.poll();
+        let registers = tmc::registers(&controller.config);
+        assert_eq!(controller.motor.driver.chopconf, registers[2].1);
+        assert_eq!(controller.motor.driver.ifcnt, 3);
+
+        send(&mut controller, "tmc r=1000\n");
+        assert_eq!(
+            controller.motor.driver.ihold_irun >> 8 & 0x1F,
+            tmc::current_scale(1000) as u32
+        );
+
+        // The chopper is only switched at a standstill.
+        send(&mut controller, "tmc c=p\n");
+        assert_eq!(controller.config.chopper, Chopper::StealthChop);
+        send(&mut controller, "h\n");
+        send(&mut controller, "tmc c=p\n");
+        assert_eq!(controller.motor.driver.gconf & 0b100, 0b100);
+
+        controller.motor.driver.drv_status = 1 << 1 | 1 << 31;
+        send(&mut controller, "tmc\n");
+        controller.motor.driver.disconnected = true;
+        send(&mut controller, "tmc h=200\n");
+
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("Driver: overtemperature! standstill CS 0\n"));
+        assert!(output.contains("Driver not answering!\nHold current: 200mA\n"));
+        assert_eq!(controller.config.hold_current_ma, 200);
+    }
+
+    #[test]
+    fn panic_is_reported_once() {
+        let mut storage = MockStorage::default();
+        let (address, record) = eeprom::encode_panic("src/timer.rs", 42);
+        storage.write(address, &record);
+
+        let controller = boot(storage, ResetCause::Watchdog);
+        assert_eq!(controller.resets.last, Some(ResetCause::Panic));
+
+        let mut controller = boot(controller.storage, ResetCause::PowerOn);
+        assert_eq!(controller.resets.get(ResetCause::Panic), 1);
+        send(&mut controller, "s\n");
+        assert!(controller
+            .serial_handler
+            .port()
+            .output
+            .contains("Last panic: timer.rs:42 "));
+    }
+
+    #[test]
+    fn reset_causes_are_counted() {
+        let mut controller = controller();
+        send(&mut controller, "r\n");
+
+        // The requested reset is done by the watchdog.
+        let controller = boot(controller.storage, ResetCause::Watchdog);
+        let mut controller = boot(controller.storage, ResetCause::Watchdog);
+        send(&mut controller, "s\n");
+
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("Last reset: watchdog "));
+        assert!(output.contains("Resets: pwr 1 ext 0 bod 0 wdt 1 cmd 1 "));
+    }
+
+    #[test]
+    fn closed_end_switch_raises_a_fault() {
+        let mut controller = controller();
+        controller.motor.position = 500;
+        send(&mut controller, "w\n");
+        assert_eq!(controller.eq_tracker.get_state(), State::Rewinding);
+
+        controller.motor.limit_hit = true;
+        controller.poll();
+        send(&mut controller, "l\n");
+
+        assert_eq!(controller.eq_tracker.get_state(), State::Fault);
+        assert!(!controller.motor.stepping);
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("End switch closed!\n"));
+        assert!(output.contains("Rewinding -> Fault (fault, limit)\n"));
+    }
+
+    #[test]
+    fn history_starts_with_the_reset_cause() {
+        let mut controller = controller();
+        send(&mut controller, "n=h\n");
+        send(&mut controller, "r\n");
+
+        // The requested reset is done by the watchdog.
+        let mut controller = boot(controller.storage, ResetCause::Watchdog);
+        send(&mut controller, "l\n");
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("History:\n0 Hold -> Hold (hold, boot after command)\n"));
+
+        let mut controller = boot(controller.storage, ResetCause::Watchdog);
+        send(&mut controller, "l\n");
+        let output = &controller.serial_handler.port().output;
+        assert!(output.contains("History:\n0 Hold -> Hold (hold, boot after watchdog)\n"));
+    }
+}
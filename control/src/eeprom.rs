@@ -0,0 +1,860 @@
+//! This module handles the layout of the EEPROM.
+//! The settings live in one configuration block with a magic number, a layout
+//! version and a CRC, so a fresh or corrupted EEPROM is detected and the
+//! compiled-in defaults are used instead. Older firmware versions stored the
+//! tracking time unchecked at a fixed address. It is migrated on the first start.
+//! Several named profiles hold complete sets of settings, for platforms that
+//! share one controller. The selected profile replaces the settings on boot.
+//! The settings and the profiles are double-buffered: every record has two
+//! slots and each write goes to the slot not in use. The generation byte at the
+//! end of the slot is bumped after the data. The storage may finish the writes
+//! in the background, but keeps their order. If the power drops in between, the
+//! other slot still holds the previous value, and a slot whose data did not
+//! make it fails its CRC. So a record is always either the old or the new one.
+//! Whether the data arrived is checked by reading it back once the storage is idle.
+//! The parked state has to survive a power cycle, so it is stored
+//! together with the step position and the state to resume with.
+//! The values that change often, like the number of runtimes of the program,
+//! live in the wear-levelled log of the `wear_log` module at the end of the EEPROM.
+//! The reset counters of the `reset` module have a log of their own at the start.
+//! The location of the last panic is written by the panic handler of the firmware.
+
+use embedded_time::duration::*;
+
+use crate::config::{Config, Origin, ProfileName, PROFILE_NAME_LENGTH};
+use crate::hardware::Storage;
+use crate::link::LinkLoss;
+use crate::startup::PowerOn;
+use crate::state_machine::State;
+use crate::tmc::Chopper;
+
+const LEGACY_ADDR_STARTUPS: u16 = 0x0000;
+const BASE_ADDR_PARKED: u16 = 0x0104;
+const BASE_ADDR_BOOT_PROFILE: u16 = 0x010A;
+/// The first slots are where the records lived before they were double-buffered.
+const BASE_ADDR_CONFIG: [u16; 2] = [0x0110, 0x0200];
+const BASE_ADDR_PROFILES: [u16; 2] = [0x0140, 0x0230];
+const BASE_ADDR_PANIC: u16 = 0x02F0;
+
+/// Every buffer slot ends with its generation byte.
+const SLOT_LENGTH: u16 = 48;
+const GENERATION_OFFSET: u16 = SLOT_LENGTH - 1;
+
+/// Every profile holds the name and a configuration block.
+pub const PROFILE_SLOTS: u8 = 4;
+
+/// The legacy layout only held the tracking time.
+const LEGACY_ADDR_TIME: u16 = 0x00F0;
+
+const CONFIG_MAGIC: [u8; 2] = *b"EQ";
+pub const CONFIG_VERSION: u8 = 5;
+const HEADER_LENGTH: usize = 4;
+/// The part of a block that tells its length, see `block_length`.
+pub const BLOCK_HEADER_LENGTH: usize = HEADER_LENGTH;
+const CRC_LENGTH: usize = 2;
+const PAYLOAD_LENGTH_V1: usize = 19;
+const PAYLOAD_LENGTH_V2: usize = 22;
+const PAYLOAD_LENGTH_V3: usize = 25;
+const PAYLOAD_LENGTH_V4: usize = 29;
+/// A profile with its name and this block just fills its slot.
+const PAYLOAD_LENGTH_V5: usize = 32;
+const PAYLOAD_LENGTH: usize = PAYLOAD_LENGTH_V5;
+pub const CONFIG_BLOCK_LENGTH: usize = HEADER_LENGTH + PAYLOAD_LENGTH + CRC_LENGTH;
+/// The block of the configuration may fill its slot up to the generation byte,
+/// the one of a profile has the name in front. The profiles leave no room, a
+/// longer payload needs a new layout of the slots.
+const MAX_BLOCK_LENGTH: usize = GENERATION_OFFSET as usize;
+const _: () = assert!(PROFILE_NAME_LENGTH + CONFIG_BLOCK_LENGTH <= GENERATION_OFFSET as usize);
+
+const POWER_ON_TRACK: u8 = 0;
+const POWER_ON_HOLD: u8 = 1;
+const POWER_ON_DELAYED: u8 = 2;
+
+const LINK_LOSS_TRACK: u8 = 0;
+const LINK_LOSS_HOLD: u8 = 1;
+
+/// The flags of the TMC driver settings.
+const DRIVER_SPREAD_CYCLE: u8 = 1 << 0;
+const DRIVER_INTERPOLATION: u8 = 1 << 1;
+
+/// The panic record holds its state, the line, the end of the file name and the CRC.
+pub const PANIC_FILE_LENGTH: usize = 9;
+pub const PANIC_RECORD_LENGTH: usize = 16;
+/// The panic has not been counted as the cause of a reset yet.
+const PANIC_PENDING: u8 = 1;
+const PANIC_REPORTED: u8 = 0;
+
+const PARKED: u8 = 1;
+const RESUME_HOLD: u8 = 0;
+const RESUME_TRACKING: u8 = 1;
+
+/// What is stored while the platform is parked.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParkRecord {
+    pub position: i32,
+    /// The state that is restored when unparking.
+    pub resume: State,
+}
+
+/// Loads the configuration block. A block of an older version and the values
+/// of the legacy layout are migrated and written back in the current layout.
+/// If there is nothing usable, the compiled-in defaults are returned.
+pub fn load_config<S: Storage>(storage: &mut S) -> (Config, Origin) {
+    if let Some((_, config, version)) = current_slot(storage, BASE_ADDR_CONFIG, 0) {
+        if version >= CONFIG_VERSION {
+            return (config, Origin::Stored);
+        }
+
+        write_config(&config, storage);
+        return (config, Origin::Migrated);
+    }
+
+    match read_legacy_config(storage) {
+        Some(config) => {
+            write_config(&config, storage);
+            // The legacy values are stale from now on, so they must never be migrated again.
+            storage.write(LEGACY_ADDR_TIME, &[0xFF; 4]);
+            (config, Origin::Migrated)
+        }
+        None => (Config::default(), Origin::Defaults),
+    }
+}
+
+pub fn write_config<S: Storage>(config: &Config, storage: &mut S) {
+    write_slot(storage, BASE_ADDR_CONFIG, &[], &encode_config(config));
+}
+
+/// Returns the stored configuration without migrating anything.
+pub fn read_config<S: Storage>(storage: &S) -> Option<Config> {
+    current_slot(storage, BASE_ADDR_CONFIG, 0).map(|(_, config, _)| config)
+}
+
+/// Returns the name and the settings of the profile, if the slot holds a valid one.
+pub fn read_profile<S: Storage>(slot: u8, storage: &S) -> Option<(ProfileName, Config)> {
+    if slot >= PROFILE_SLOTS {
+        return None;
+    }
+
+    let slots = profile_slots(slot);
+    let (index, config, _) = current_slot(storage, slots, PROFILE_NAME_LENGTH as u16)?;
+
+    let mut name = [0_u8; PROFILE_NAME_LENGTH];
+    storage.read(slots[index], &mut name);
+    Some((ProfileName(name), config))
+}
+
+pub fn write_profile<S: Storage>(slot: u8, name: &ProfileName, config: &Config, storage: &mut S) {
+    write_slot(
+        storage,
+        profile_slots(slot),
+        &name.0,
+        &encode_config(config),
+    );
+}
+
+/// Invalidates both buffer slots, so the profile reads as empty.
+pub fn clear_profile<S: Storage>(slot: u8, storage: &mut S) {
+    clear_slots(storage, profile_slots(slot), PROFILE_NAME_LENGTH as u16);
+}
+
+/// Invalidates both buffer slots of the configuration, so the next start
+/// uses the defaults. The legacy values must not be migrated instead.
+pub fn clear_config<S: Storage>(storage: &mut S) {
+    storage.write(LEGACY_ADDR_TIME, &[0xFF; 4]);
+    clear_slots(storage, BASE_ADDR_CONFIG, 0);
+}
+
+/// The slot in use is invalidated last, an interrupted clear keeps the record.
+fn clear_slots<S: Storage>(storage: &mut S, slots: [u16; 2], offset: u16) {
+    let current = current_slot(storage, slots, offset).map_or(0, |(index, _, _)| index);
+
+    for index in [1 - current, current].iter() {
+        storage.write(slots[*index] + offset, &[0xFF; HEADER_LENGTH]);
+    }
+}
+
+/// The buffer slot not in use, for a record that is written in parts. It only
+/// becomes the current one with `commit`, until then the record keeps its
+/// old value, so the writes can be abandoned halfway.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Staging {
+    address: u16,
+    /// The offset of the configuration block, behind the profile name.
+    offset: u16,
+    generation: u8,
+}
+
+impl Staging {
+    /// The room for the name and the block, in front of the generation byte.
+    pub const ROOM: usize = GENERATION_OFFSET as usize;
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    pub fn block_address(&self) -> u16 {
+        self.address + self.offset
+    }
+
+    /// Makes the slot the current one.
+    pub fn commit<S: Storage>(&self, storage: &mut S) {
+        storage.write(self.address + GENERATION_OFFSET, &[self.generation]);
+    }
+}
+
+pub fn stage_config<S: Storage>(storage: &S) -> Staging {
+    stage(storage, BASE_ADDR_CONFIG, 0)
+}
+
+/// The name goes in front of the block.
+pub fn stage_profile<S: Storage>(slot: u8, storage: &S) -> Staging {
+    stage(storage, profile_slots(slot), PROFILE_NAME_LENGTH as u16)
+}
+
+fn profile_slots(slot: u8) -> [u16; 2] {
+    let offset = slot as u16 * SLOT_LENGTH;
+    [
+        BASE_ADDR_PROFILES[0] + offset,
+        BASE_ADDR_PROFILES[1] + offset,
+    ]
+}
+
+/// Finds the buffer slot with the newest valid configuration block. The block
+/// starts at the offset within the slot. Returns the index of the slot, the
+/// configuration and the version of its layout.
+fn current_slot<S: Storage>(
+    storage: &S,
+    slots: [u16; 2],
+    offset: u16,
+) -> Option<(usize, Config, u8)> {
+    let mut current: Option<(usize, Config, u8, u8)> = None;
+
+    for (index, address) in slots.iter().enumerate() {
+        let room = (GENERATION_OFFSET - offset) as usize;
+        if let Some((config, version)) = read_config_block(storage, address + offset, room) {
+            let mut generation = [0_u8; 1];
+            storage.read(address + GENERATION_OFFSET, &mut generation);
+
+            // The generation wraps, so the newer slot is the one ahead of the other.
+            let is_newer = match current {
+                Some((_, _, _, current_generation)) => {
+                    (generation[0].wrapping_sub(current_generation) as i8) > 0
+                }
+                None => true,
+            };
+            if is_newer {
+                current = Some((index, config, version, generation[0]));
+            }
+        }
+    }
+
+    current.map(|(index, config, version, _)| (index, config, version))
+}
+
+/// Writes the prefix and the block to the slot not in use.
+/// Then the bumped generation makes this slot the current one.
+fn write_slot<S: Storage>(storage: &mut S, slots: [u16; 2], prefix: &[u8], block: &[u8]) {
+    let staging = stage(storage, slots, prefix.len() as u16);
+
+    storage.write(staging.address, prefix);
+    storage.write(staging.address + staging.offset, block);
+    staging.commit(storage);
+}
+
+/// The slot not in use and the generation that makes it the current one.
+fn stage<S: Storage>(storage: &S, slots: [u16; 2], offset: u16) -> Staging {
+    let (index, generation) = match current_slot(storage, slots, offset) {
+        Some((current, _, _)) => {
+            let mut generation = [0_u8; 1];
+            storage.read(slots[current] + GENERATION_OFFSET, &mut generation);
+            (1 - current, generation[0].wrapping_add(1))
+        }
+        None => (0, 0),
+    };
+
+    Staging {
+        address: slots[index],
+        offset,
+        generation,
+    }
+}
+
+/// The slot of the profile that is loaded on boot. It is stored together with
+/// its complement, so an erased or corrupted value is not mistaken for a slot.
+pub fn read_boot_profile<S: Storage>(storage: &S) -> Option<u8> {
+    let mut boot = [0_u8; 2];
+    storage.read(BASE_ADDR_BOOT_PROFILE, &mut boot);
+
+    Some(boot[0]).filter(|slot| boot[1] == !slot && *slot < PROFILE_SLOTS)
+}
+
+pub fn write_boot_profile<S: Storage>(slot: Option<u8>, storage: &mut S) {
+    match slot {
+        Some(slot) => storage.write(BASE_ADDR_BOOT_PROFILE, &[slot, !slot]),
+        None => storage.write(BASE_ADDR_BOOT_PROFILE, &[0xFF, 0xFF]),
+    }
+}
+
+/// A block longer than the room in front of the generation byte is refused.
+fn read_config_block<S: Storage>(storage: &S, address: u16, room: usize) -> Option<(Config, u8)> {
+    let mut block = [0_u8; MAX_BLOCK_LENGTH];
+    storage.read(address, &mut block[..HEADER_LENGTH]);
+
+    let length = HEADER_LENGTH + block[3] as usize + CRC_LENGTH;
+    if length > room.min(MAX_BLOCK_LENGTH) {
+        return None;
+    }
+    storage.read(
+        address + HEADER_LENGTH as u16,
+        &mut block[HEADER_LENGTH..length],
+    );
+
+    decode_config(&block[..length])
+}
+
+/// The length of the whole block that starts with the given header.
+pub fn block_length(block: &[u8]) -> Option<usize> {
+    if block.len() < HEADER_LENGTH {
+        return None;
+    }
+    Some(HEADER_LENGTH + block[3] as usize + CRC_LENGTH)
+}
+
+/// The block consists of the magic number, the layout version, the length of
+/// the payload, the payload and the CRC of everything in front of it.
+pub fn encode_config(config: &Config) -> [u8; CONFIG_BLOCK_LENGTH] {
+    let mut block = [0_u8; CONFIG_BLOCK_LENGTH];
+    block[..2].copy_from_slice(&CONFIG_MAGIC);
+    block[2] = CONFIG_VERSION;
+    block[3] = PAYLOAD_LENGTH as u8;
+
+    let (mode, minutes) = match config.power_on {
+        PowerOn::Track => (POWER_ON_TRACK, 0),
+        PowerOn::Hold => (POWER_ON_HOLD, 0),
+        PowerOn::Delayed(minutes) => (POWER_ON_DELAYED, minutes),
+    };
+
+    let payload = &mut block[HEADER_LENGTH..HEADER_LENGTH + PAYLOAD_LENGTH];
+    payload[0..4].copy_from_slice(&config.waiting_time.integer().to_be_bytes());
+    payload[4..8].copy_from_slice(&config.trim_ppm.to_be_bytes());
+    payload[8..12].copy_from_slice(&config.travel_limit.unwrap_or(-1).to_be_bytes());
+    payload[12..16].copy_from_slice(&config.park_position.to_be_bytes());
+    payload[16] = mode;
+    payload[17..19].copy_from_slice(&minutes.to_be_bytes());
+    payload[19] = config.microsteps;
+    payload[20] = config.reversed as u8;
+    payload[21] = config.guide_rate_percent;
+    payload[22..24].copy_from_slice(&config.link_timeout_seconds.to_be_bytes());
+    payload[24] = match config.link_loss {
+        LinkLoss::Track => LINK_LOSS_TRACK,
+        LinkLoss::Hold => LINK_LOSS_HOLD,
+    };
+    payload[25..27].copy_from_slice(&config.supply_warning_mv.unwrap_or(0).to_be_bytes());
+    payload[27..29].copy_from_slice(&config.supply_cutoff_mv.unwrap_or(0).to_be_bytes());
+    payload[29] = (config.run_current_ma / 10) as u8;
+    payload[30] = (config.hold_current_ma / 10) as u8;
+    payload[31] = match config.chopper {
+        Chopper::StealthChop => 0,
+        Chopper::SpreadCycle => DRIVER_SPREAD_CYCLE,
+    } | if config.interpolation {
+        DRIVER_INTERPOLATION
+    } else {
+        0
+    };
+
+    let crc = crc16(&block[..HEADER_LENGTH + PAYLOAD_LENGTH]);
+    block[HEADER_LENGTH + PAYLOAD_LENGTH..].copy_from_slice(&crc.to_be_bytes());
+
+    block
+}
+
+/// Checks the block and returns the configuration and the version of its layout.
+pub fn decode_config(block: &[u8]) -> Option<(Config, u8)> {
+    if block.len() < HEADER_LENGTH + CRC_LENGTH || block[..2] != CONFIG_MAGIC {
+        return None;
+    }
+
+    let version = block[2];
+    let end = HEADER_LENGTH + block[3] as usize;
+    if block.len() < end + CRC_LENGTH {
+        return None;
+    }
+
+    let crc = u16::from_be_bytes([block[end], block[end + 1]]);
+    if crc != crc16(&block[..end]) {
+        return None;
+    }
+
+    let config = decode_payload(version, &block[HEADER_LENGTH..end])?;
+    Some((config, version))
+}
+
+/// Every version of the layout only appends fields to the payload of its predecessor.
+/// So a payload of an older version is migrated by keeping the defaults for the
+/// fields it lacks. A newer payload is read as far as its fields are known.
+fn decode_payload(version: u8, payload: &[u8]) -> Option<Config> {
+    if version < 1 || payload.len() < PAYLOAD_LENGTH_V1 {
+        return None;
+    }
+
+    // Version 1
+    let mut config = Config {
+        waiting_time: Microseconds(u8_to_u32(word(payload, 0))),
+        trim_ppm: i32::from_be_bytes(word(payload, 4)),
+        travel_limit: Some(i32::from_be_bytes(word(payload, 8))),
+        park_position: i32::from_be_bytes(word(payload, 12)),
+        power_on: power_on(payload[16], [payload[17], payload[18]]),
+        ..Config::default()
+    };
+
+    // Version 2
+    if version >= 2 && payload.len() >= PAYLOAD_LENGTH_V2 {
+        config.microsteps = payload[19];
+        config.reversed = payload[20] != 0;
+        config.guide_rate_percent = payload[21];
+    }
+
+    // Version 3
+    if version >= 3 && payload.len() >= PAYLOAD_LENGTH_V3 {
+        config.link_timeout_seconds = u16::from_be_bytes([payload[22], payload[23]]);
+        config.link_loss = match payload[24] {
+            LINK_LOSS_HOLD => LinkLoss::Hold,
+            _ => LinkLoss::Track,
+        };
+    }
+
+    // Version 4, a threshold of zero is disabled.
+    if version >= 4 && payload.len() >= PAYLOAD_LENGTH_V4 {
+        config.supply_warning_mv = Some(u16::from_be_bytes([payload[25], payload[26]]));
+        config.supply_cutoff_mv = Some(u16::from_be_bytes([payload[27], payload[28]]));
+    }
+
+    // Version 5
+    if version >= 5 && payload.len() >= PAYLOAD_LENGTH_V5 {
+        config.run_current_ma = payload[29] as u16 * 10;
+        config.hold_current_ma = payload[30] as u16 * 10;
+        config.chopper = if payload[31] & DRIVER_SPREAD_CYCLE != 0 {
+            Chopper::SpreadCycle
+        } else {
+            Chopper::StealthChop
+        };
+        config.interpolation = payload[31] & DRIVER_INTERPOLATION != 0;
+    }
+
+    // The fields of later versions are read here, each one guarded by the version
+    // of the block. Fields missing in older blocks keep their defaults.
+
+    Some(config.sanitized())
+}
+
+/// The tracking time of the firmware versions without a configuration block,
+/// stored unchecked at a fixed address. An erased tracking time means that the
+/// chip has never been configured. Everything else gets the defaults.
+fn read_legacy_config<S: Storage>(storage: &S) -> Option<Config> {
+    let mut time = [0_u8; 4];
+    storage.read(LEGACY_ADDR_TIME, &mut time);
+    if time == [0xFF; 4] {
+        return None;
+    }
+
+    let config = Config {
+        waiting_time: Microseconds(u8_to_u32(time)),
+        ..Config::default()
+    };
+    Some(config.sanitized())
+}
+
+/// An unknown mode, like the one of an erased EEPROM, starts tracking right away.
+fn power_on(mode: u8, minutes: [u8; 2]) -> PowerOn {
+    match mode {
+        POWER_ON_HOLD => PowerOn::Hold,
+        POWER_ON_DELAYED => PowerOn::Delayed(u16::from_be_bytes(minutes)),
+        _ => PowerOn::Track,
+    }
+}
+
+fn word(data: &[u8], offset: usize) -> [u8; 4] {
+    [
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ]
+}
+
+/// CRC-16/CCITT-FALSE, calculated bitwise to save flash.
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    crc16_update(CRC16_INIT, data)
+}
+
+pub(crate) const CRC16_INIT: u16 = 0xFFFF;
+
+/// Continues the CRC over the next part of the data.
+pub(crate) fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+pub fn read_park<S: Storage>(storage: &S) -> Option<ParkRecord> {
+    let mut park = [0_u8; 6];
+    storage.read(BASE_ADDR_PARKED, &mut park);
+
+    if park[0] != PARKED {
+        return None;
+    }
+
+    Some(ParkRecord {
+        position: i32::from_be_bytes([park[2], park[3], park[4], park[5]]),
+        resume: match park[1] {
+            RESUME_TRACKING => State::Tracking,
+            _ => State::Hold,
+        },
+    })
+}
+
+/// Where the firmware panicked last.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PanicRecord {
+    pub line: u32,
+    /// The end of the path of the source file, padded with zeros.
+    pub file: [u8; PANIC_FILE_LENGTH],
+}
+
+impl PanicRecord {
+    pub fn file(&self) -> &str {
+        let length = self
+            .file
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(PANIC_FILE_LENGTH);
+        core::str::from_utf8(&self.file[..length]).unwrap_or("?")
+    }
+}
+
+/// Builds the record the panic handler writes, together with its address.
+/// It can not use the storage, which may be the very thing that panicked.
+pub fn encode_panic(file: &str, line: u32) -> (u16, [u8; PANIC_RECORD_LENGTH]) {
+    let mut record = [0_u8; PANIC_RECORD_LENGTH];
+    record[0] = PANIC_PENDING;
+    record[1..5].copy_from_slice(&line.to_be_bytes());
+
+    // The file name tells most. Of a long one the end is kept.
+    let name = file.rsplit('/').next().unwrap_or(file).as_bytes();
+    let tail = &name[name.len().saturating_sub(PANIC_FILE_LENGTH)..];
+    record[5..5 + tail.len()].copy_from_slice(tail);
+
+    let crc = crc16(&record[1..PANIC_RECORD_LENGTH - 2]);
+    record[PANIC_RECORD_LENGTH - 2..].copy_from_slice(&crc.to_be_bytes());
+    (BASE_ADDR_PANIC, record)
+}
+
+/// Returns the last panic and whether it still has to be counted.
+pub fn read_panic<S: Storage>(storage: &S) -> Option<(PanicRecord, bool)> {
+    let mut record = [0_u8; PANIC_RECORD_LENGTH];
+    storage.read(BASE_ADDR_PANIC, &mut record);
+
+    let crc = u16::from_be_bytes([
+        record[PANIC_RECORD_LENGTH - 2],
+        record[PANIC_RECORD_LENGTH - 1],
+    ]);
+    if crc != crc16(&record[1..PANIC_RECORD_LENGTH - 2]) {
+        return None;
+    }
+
+    let mut file = [0_u8; PANIC_FILE_LENGTH];
+    file.copy_from_slice(&record[5..5 + PANIC_FILE_LENGTH]);
+    let panic = PanicRecord {
+        line: u32::from_be_bytes(word(&record, 1)),
+        file,
+    };
+    Some((panic, record[0] == PANIC_PENDING))
+}
+
+/// Marks the panic as counted. The record is kept for the status.
+pub fn acknowledge_panic<S: Storage>(storage: &mut S) {
+    storage.write(BASE_ADDR_PANIC, &[PANIC_REPORTED]);
+}
+
+/// Stores the parked state or, with `None`, clears it.
+pub fn write_park<S: Storage>(park: Option<ParkRecord>, storage: &mut S) {
+    match park {
+        Some(park) => {
+            let resume = match park.resume {
+                State::Tracking => RESUME_TRACKING,
+                _ => RESUME_HOLD,
+            };
+            let position = park.position.to_be_bytes();
+
+            storage.write(
+                BASE_ADDR_PARKED,
+                &[
+                    PARKED,
+                    resume,
+                    position[0],
+                    position[1],
+                    position[2],
+                    position[3],
+                ],
+            );
+        }
+        // Only the flag has to be cleared.
+        None => storage.write(BASE_ADDR_PARKED, &[0]),
+    }
+}
+
+/// Older firmware versions counted the starts at a fixed address. The count
+/// is taken over by the wear-levelled log. An erased counter is zero.
+pub fn read_legacy_startups<S: Storage>(storage: &S) -> u32 {
+    let mut startups = [0_u8; 4];
+    storage.read(LEGACY_ADDR_STARTUPS, &mut startups);
+
+    match u8_to_u32(startups) {
+        0xFFFF_FFFF => 0,
+        startups => startups,
+    }
+}
+
+fn u8_to_u32(number_array: [u8; 4]) -> u32 {
+    let mut result;
+
+    result = (number_array[0] as u32) << 24;
+    result += (number_array[1] as u32) << 16;
+    result += (number_array[2] as u32) << 8;
+    result += number_array[3] as u32;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockStorage;
+
+    #[test]
+    fn test_conversion() {
+        assert_eq!(u8_to_u32([0, 0, 0, 0]), 0);
+        assert_eq!(u8_to_u32([0x12, 0x34, 0x56, 0x78]), 0x1234_5678);
+    }
+
+    #[test]
+    fn erased_legacy_startups_are_zero() {
+        let mut storage = MockStorage::default();
+        assert_eq!(read_legacy_startups(&storage), 0);
+
+        storage.write(LEGACY_ADDR_STARTUPS, &[0, 0, 1, 2]);
+        assert_eq!(read_legacy_startups(&storage), 0x0102);
+    }
+
+    #[test]
+    fn crc_matches_the_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn config_is_stored_in_a_checked_block() {
+        let mut storage = MockStorage::default();
+        assert_eq!(
+            load_config(&mut storage),
+            (Config::default(), Origin::Defaults)
+        );
+
+        let config = Config {
+            waiting_time: Microseconds(25_000),
+            trim_ppm: -120,
+            travel_limit: Some(50_000),
+            park_position: 100,
+            power_on: PowerOn::Delayed(300),
+            microsteps: 16,
+            reversed: true,
+            guide_rate_percent: 30,
+            link_timeout_seconds: 15,
+            link_loss: LinkLoss::Hold,
+            supply_warning_mv: Some(11_500),
+            supply_cutoff_mv: None,
+            run_current_ma: 1_200,
+            hold_current_ma: 0,
+            chopper: Chopper::SpreadCycle,
+            interpolation: false,
+        };
+        write_config(&config, &mut storage);
+        assert_eq!(load_config(&mut storage), (config, Origin::Stored));
+
+        // A flipped bit invalidates the block.
+        storage.data[BASE_ADDR_CONFIG[0] as usize + HEADER_LENGTH] ^= 0x01;
+        assert_eq!(
+            load_config(&mut storage),
+            (Config::default(), Origin::Defaults)
+        );
+    }
+
+    #[test]
+    fn legacy_layout_is_migrated_once() {
+        let mut storage = MockStorage::default();
+        storage.write(LEGACY_ADDR_TIME, &25_000_u32.to_be_bytes());
+
+        let (config, origin) = load_config(&mut storage);
+        assert_eq!(origin, Origin::Migrated);
+        assert_eq!(
+            config,
+            Config {
+                waiting_time: Microseconds(25_000),
+                ..Config::default()
+            }
+        );
+
+        assert_eq!(load_config(&mut storage), (config, Origin::Stored));
+    }
+
+    #[test]
+    fn version_1_block_is_migrated() {
+        let mut storage = MockStorage::default();
+        let config = Config {
+            waiting_time: Microseconds(25_000),
+            ..Config::default()
+        };
+
+        // A version 1 block is a current block without the fields added later.
+        let mut block = encode_config(&config);
+        block[2] = 1;
+        block[3] = PAYLOAD_LENGTH_V1 as u8;
+        let end = HEADER_LENGTH + PAYLOAD_LENGTH_V1;
+        let crc = crc16(&block[..end]);
+        block[end..end + CRC_LENGTH].copy_from_slice(&crc.to_be_bytes());
+        storage.write(BASE_ADDR_CONFIG[0], &block[..end + CRC_LENGTH]);
+
+        assert_eq!(load_config(&mut storage), (config, Origin::Migrated));
+        assert_eq!(load_config(&mut storage), (config, Origin::Stored));
+    }
+
+    #[test]
+    fn block_reaching_past_its_slot_is_refused() {
+        let mut storage = MockStorage::default();
+        // One byte longer than the room a profile leaves.
+        let payload_length =
+            GENERATION_OFFSET as usize - PROFILE_NAME_LENGTH - HEADER_LENGTH - CRC_LENGTH + 1;
+        let end = HEADER_LENGTH + payload_length;
+        let mut block = [0_u8; MAX_BLOCK_LENGTH];
+        block[..CONFIG_BLOCK_LENGTH].copy_from_slice(&encode_config(&Config::default()));
+        block[3] = payload_length as u8;
+        let crc = crc16(&block[..end]);
+        block[end..end + CRC_LENGTH].copy_from_slice(&crc.to_be_bytes());
+        let block = &block[..end + CRC_LENGTH];
+
+        // The configuration has no name in front, so the block fits its slot.
+        storage.write(BASE_ADDR_CONFIG[0], block);
+        assert!(read_config(&storage).is_some());
+
+        let slot = profile_slots(0)[0];
+        storage.write(slot, b"too long");
+        storage.write(slot + PROFILE_NAME_LENGTH as u16, block);
+        assert!(read_profile(0, &storage).is_none());
+    }
+
+    #[test]
+    fn profiles_are_stored_in_slots() {
+        let mut storage = MockStorage::default();
+        assert_eq!(read_profile(0, &storage), None);
+        assert_eq!(read_boot_profile(&storage), None);
+
+        let name = ProfileName::new("barndoor");
+        let config = Config {
+            microsteps: 8,
+            ..Config::default()
+        };
+        write_profile(PROFILE_SLOTS - 1, &name, &config, &mut storage);
+        write_boot_profile(Some(PROFILE_SLOTS - 1), &mut storage);
+
+        assert_eq!(
+            read_profile(PROFILE_SLOTS - 1, &storage),
+            Some((name, config))
+        );
+        assert_eq!(read_profile(PROFILE_SLOTS, &storage), None);
+        assert_eq!(read_boot_profile(&storage), Some(PROFILE_SLOTS - 1));
+
+        write_profile(PROFILE_SLOTS - 1, &name, &Config::default(), &mut storage);
+        clear_profile(PROFILE_SLOTS - 1, &mut storage);
+        assert_eq!(read_profile(PROFILE_SLOTS - 1, &storage), None);
+    }
+
+    #[test]
+    fn interrupted_writes_keep_the_previous_value() {
+        let old = Config {
+            waiting_time: Microseconds(25_000),
+            ..Config::default()
+        };
+        let new = Config {
+            waiting_time: Microseconds(26_000),
+            ..Config::default()
+        };
+
+        // Cut the power after every single byte of the second write,
+        // up to the generation byte that would make the new value current.
+        for written_bytes in 0..=CONFIG_BLOCK_LENGTH {
+            let mut storage = MockStorage::default();
+            write_config(&old, &mut storage);
+            write_config(&old, &mut storage);
+
+            storage.fail_after(written_bytes);
+            write_config(&new, &mut storage);
+            assert_eq!(load_config(&mut storage), (old, Origin::Stored));
+        }
+
+        let mut storage = MockStorage::default();
+        write_config(&old, &mut storage);
+        write_config(&new, &mut storage);
+        assert_eq!(read_config(&storage), Some(new));
+    }
+
+    #[test]
+    fn generations_wrap_around() {
+        let mut storage = MockStorage::default();
+        for time in 0..300 {
+            let config = Config {
+                waiting_time: Microseconds(20_000 + time),
+                ..Config::default()
+            };
+            write_config(&config, &mut storage);
+            assert_eq!(load_config(&mut storage), (config, Origin::Stored));
+        }
+    }
+
+    #[test]
+    fn panic_record_is_read_back() {
+        let mut storage = MockStorage::default();
+        assert_eq!(read_panic(&storage), None);
+
+        let (address, record) = encode_panic("firmware/src/serial.rs", 89);
+        storage.write(address, &record);
+        let (panic, pending) = read_panic(&storage).unwrap();
+        assert_eq!((panic.file(), panic.line, pending), ("serial.rs", 89, true));
+
+        acknowledge_panic(&mut storage);
+        assert_eq!(read_panic(&storage), Some((panic, false)));
+    }
+
+    #[test]
+    fn park_record_is_stored() {
+        let mut storage = MockStorage::default();
+        assert_eq!(read_park(&storage), None);
+
+        let park = ParkRecord {
+            position: -1234,
+            resume: State::Tracking,
+        };
+        write_park(Some(park), &mut storage);
+        assert_eq!(read_park(&storage), Some(park));
+
+        write_park(None, &mut storage);
+        assert_eq!(read_park(&storage), None);
+    }
+}
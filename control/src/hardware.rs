@@ -0,0 +1,116 @@
+//! The small traits the control logic uses to talk to the hardware.
+
+use embedded_time::duration::*;
+
+use crate::state_machine::Direction;
+
+/// The step, direction and enable outputs of the motor driver.
+pub trait StepperOutput {
+    fn set_direction(&mut self, direction: Direction);
+
+    /// A disabled driver leaves the motor without holding torque.
+    fn set_enabled(&mut self, enabled: bool);
+
+    /// Selects the microsteps per full step, a power of two up to 32.
+    fn set_microsteps(&mut self, microsteps: u8);
+
+    /// Swaps the meaning of the direction pin for a motor that is mounted the other
+    /// way round. The position is still counted in the direction of the platform.
+    fn set_reversed(&mut self, reversed: bool);
+
+    /// Returns the number of steps done since startup.
+    fn position(&self) -> i32;
+
+    /// Overwrites the step counter, e.g. with the position stored when parking.
+    fn set_position(&mut self, position: i32);
+
+    /// Tells whether a closed end switch held back a step since the last call.
+    fn take_limit_hit(&mut self) -> bool;
+}
+
+/// The timer which generates the step pulses.
+pub trait StepTimer {
+    fn set_step_time(&mut self, time: Microseconds);
+    fn set_stepping(&mut self, active: bool);
+}
+
+/// A byte addressed persistent storage like the EEPROM. Writes may be finished in
+/// the background in the order they were issued. Reads already see their data.
+pub trait Storage {
+    fn read(&self, address: u16, buffer: &mut [u8]);
+    fn write(&mut self, address: u16, data: &[u8]);
+
+    /// Tells whether written data is still on its way to the cells.
+    fn is_busy(&self) -> bool;
+}
+
+/// What went wrong while receiving.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RxError {
+    /// A buffer was full and bytes were dropped, or a line was too long.
+    Overflow,
+    /// The stop bit was missing, e.g. at a wrong baud rate or on a noisy line.
+    Framing,
+    Parity,
+    /// The UART received a byte before the last one was read.
+    Overrun,
+}
+
+impl RxError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RxError::Overflow => "overflow",
+            RxError::Framing => "framing error",
+            RxError::Parity => "parity error",
+            RxError::Overrun => "overrun",
+        }
+    }
+}
+
+/// The serial port the commands are received from.
+pub trait SerialPort {
+    /// Returns the next received byte, if there is one. Bytes that were
+    /// received with an error or were dropped show up as the error, in
+    /// their place in the stream.
+    fn read_byte(&mut self) -> Option<Result<u8, RxError>>;
+    fn write_str(&mut self, string: &str);
+}
+
+/// A monotonic clock with millisecond resolution.
+pub trait Clock {
+    /// Returns the milliseconds since startup. The value wraps after about 49 days.
+    fn millis(&self) -> u32;
+}
+
+/// The supply voltage, measured through a voltage divider.
+pub trait SupplyVoltage {
+    /// Returns a new reading in millivolts, `None` while the conversion is still running.
+    fn millivolts(&mut self) -> Option<u16>;
+}
+
+/// The single-wire UART of a TMC2208 or TMC2209 driver, see the `tmc` module.
+pub trait DriverUart {
+    fn send(&mut self, datagram: &[u8]);
+
+    /// Sends the read request and receives the reply into the buffer.
+    /// Returns false when the driver did not answer in time.
+    fn transfer(&mut self, request: &[u8], reply: &mut [u8]) -> bool;
+}
+
+/// Bundles the hardware implementations of a platform.
+pub trait Hardware {
+    #[cfg(not(feature = "tmc"))]
+    type Motor: StepperOutput + StepTimer;
+    /// A TMC driver is configured through the motor.
+    #[cfg(feature = "tmc")]
+    type Motor: StepperOutput + StepTimer + DriverUart;
+    type Storage: Storage;
+    type Serial: SerialPort;
+    type Clock: Clock;
+    #[cfg(feature = "supply")]
+    type Supply: SupplyVoltage;
+
+    /// The secret the bootloader command is authenticated with, see `auth`.
+    #[cfg(feature = "bootloader")]
+    const BOOTLOADER_KEY: crate::auth::Key;
+}
@@ -0,0 +1,165 @@
+//! A small ring buffer of the recent events of the state machine and of the
+//! commands that changed a setting of the motion. It is kept in RAM and can
+//! be dumped over serial, so the timeline of a failed exposure can be rebuilt
+//! afterwards. A reset clears it, so
+//! every boot starts it with an entry that tells why the chip restarted.
+
+use crate::reset::ResetCause;
+use crate::state_machine::{Event, State};
+
+/// The number of entries kept. Older entries are overwritten.
+pub const HISTORY_LENGTH: usize = 16;
+
+/// What triggered an event.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Cause {
+    /// The firmware started after the given reset.
+    Boot(ResetCause),
+    Command,
+    /// The countdown to the start of tracking expired.
+    Countdown,
+    GuidePulse,
+    /// The platform ran past the end of its travel or into an end switch.
+    Limit,
+    /// The target of a motion has been reached.
+    Target,
+    /// The host was silent for too long while slewing or guiding.
+    LinkLoss,
+    /// The supply voltage dropped below the cutoff.
+    Supply,
+}
+
+impl Cause {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cause::Boot(_) => "boot",
+            Cause::Command => "command",
+            Cause::Countdown => "countdown",
+            Cause::GuidePulse => "guide pulse",
+            Cause::Limit => "limit",
+            Cause::Target => "target",
+            Cause::LinkLoss => "link lost",
+            Cause::Supply => "low battery",
+        }
+    }
+}
+
+/// A setting changed by a command, with its new value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Setting {
+    /// The base waiting time in µs.
+    TrackingTime(u32),
+    TrimPpm(i32),
+    TravelLimit(Option<i32>),
+    ParkPosition(i32),
+    #[cfg(feature = "guiding")]
+    GuideRatePercent(u8),
+    /// The settings of the profile in the slot were loaded.
+    #[cfg(feature = "profiles")]
+    Profile(u8),
+    /// The settings of a backup were imported.
+    #[cfg(feature = "backup")]
+    Import,
+}
+
+impl Setting {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::TrackingTime(_) => "tracking time",
+            Setting::TrimPpm(_) => "trim",
+            Setting::TravelLimit(_) => "travel limit",
+            Setting::ParkPosition(_) => "park position",
+            #[cfg(feature = "guiding")]
+            Setting::GuideRatePercent(_) => "guide rate",
+            #[cfg(feature = "profiles")]
+            Setting::Profile(_) => "profile",
+            #[cfg(feature = "backup")]
+            Setting::Import => "import",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Change {
+    /// An event handled by the state machine.
+    Transition {
+        from: State,
+        to: State,
+        event: Event,
+        /// The event was refused, so `from` and `to` are equal.
+        refused: bool,
+    },
+    Setting(Setting),
+}
+
+#[derive(Clone, Copy)]
+pub struct Entry {
+    /// The uptime in milliseconds.
+    pub time: u32,
+    pub change: Change,
+    pub cause: Cause,
+}
+
+pub struct History {
+    entries: [Option<Entry>; HISTORY_LENGTH],
+    next: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            entries: [None; HISTORY_LENGTH],
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, entry: Entry) {
+        self.entries[self.next] = Some(entry);
+        self.next = (self.next + 1) % HISTORY_LENGTH;
+    }
+
+    /// Iterates over the entries, the oldest one first.
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        (0..HISTORY_LENGTH)
+            .filter_map(move |i| self.entries[(self.next + i) % HISTORY_LENGTH].as_ref())
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: u32) -> Entry {
+        Entry {
+            time,
+            change: Change::Transition {
+                from: State::Hold,
+                to: State::Tracking,
+                event: Event::Track,
+                refused: false,
+            },
+            cause: Cause::Command,
+        }
+    }
+
+    #[test]
+    fn oldest_entries_are_overwritten() {
+        let mut history = History::new();
+        assert_eq!(history.iter().count(), 0);
+
+        for time in 0..HISTORY_LENGTH as u32 + 3 {
+            history.record(entry(time));
+        }
+
+        let times: Vec<u32> = history.iter().map(|entry| entry.time).collect();
+        assert_eq!(times.len(), HISTORY_LENGTH);
+        assert_eq!(times[0], 3);
+        assert_eq!(times[HISTORY_LENGTH - 1], HISTORY_LENGTH as u32 + 2);
+    }
+}
@@ -0,0 +1,54 @@
+//! The hardware independent control logic of the EQ platform.
+//! All hardware access goes through the traits in the `hardware` module.
+//! The firmware implements them for the AVR, the tests use in-memory mocks.
+//! The `pins` module drives the pins of any HAL with the `embedded-hal` traits.
+//! The optional subsystems are selected with the features of the crate.
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(feature = "bootloader")]
+pub mod auth;
+#[cfg(feature = "backup")]
+pub mod backup;
+pub mod config;
+pub mod controller;
+pub mod eeprom;
+pub mod hardware;
+pub mod history;
+pub mod link;
+#[cfg(feature = "embedded-hal")]
+pub mod pins;
+pub mod rate;
+pub mod reset;
+pub mod ring_buffer;
+pub mod serial;
+pub mod session;
+pub mod startup;
+pub mod state_machine;
+#[cfg(feature = "supply")]
+pub mod supply;
+pub mod tmc;
+pub mod wear_log;
+
+#[cfg(test)]
+mod mock;
+
+pub use controller::{Control, Controller};
+
+/// The optional subsystems and whether they are compiled in.
+static FEATURES: [(&str, bool); 6] = [
+    ("guiding", cfg!(feature = "guiding")),
+    ("profiles", cfg!(feature = "profiles")),
+    ("backup", cfg!(feature = "backup")),
+    ("supply", cfg!(feature = "supply")),
+    ("bootloader", cfg!(feature = "bootloader")),
+    ("tmc", cfg!(feature = "tmc")),
+];
+
+/// The names of the optional subsystems compiled in.
+pub fn features() -> impl Iterator<Item = &'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+}
@@ -0,0 +1,58 @@
+//! Watches the link to the host. A slew or a guide pulse is ended by a command
+//! of the host, so it would go on forever when the Bluetooth link drops in the
+//! middle of it. Every received line counts as a sign of life, a host that has
+//! nothing to say sends the "q" heartbeat. When the host has been silent for
+//! the configured time, the platform falls back to tracking or holds.
+
+/// What the platform does when the host is lost while slewing or guiding.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinkLoss {
+    Track,
+    Hold,
+}
+
+impl LinkLoss {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkLoss::Track => "track",
+            LinkLoss::Hold => "hold",
+        }
+    }
+}
+
+/// Keeps the uptime in milliseconds of the last line from the host.
+/// Survives the overflow of the uptime.
+pub struct Heartbeat {
+    last_seen: u32,
+}
+
+impl Heartbeat {
+    pub fn new(now: u32) -> Self {
+        Self { last_seen: now }
+    }
+
+    pub fn seen(&mut self, now: u32) {
+        self.last_seen = now;
+    }
+
+    /// True when the host has been silent for the timeout. A timeout of zero never expires.
+    pub fn expired(&self, now: u32, timeout_seconds: u16) -> bool {
+        timeout_seconds != 0 && now.wrapping_sub(self.last_seen) >= timeout_seconds as u32 * 1000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_expires_after_the_timeout() {
+        let mut heartbeat = Heartbeat::new(u32::MAX - 500);
+        assert!(!heartbeat.expired(1000, 2));
+        assert!(heartbeat.expired(1500, 2));
+
+        heartbeat.seen(1500);
+        assert!(!heartbeat.expired(3000, 2));
+        assert!(!heartbeat.expired(60_000, 0));
+    }
+}
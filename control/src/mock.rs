@@ -0,0 +1,273 @@
+//! In-memory implementations of the hardware traits for the unit tests.
+
+use std::collections::VecDeque;
+use std::string::String;
+
+use embedded_time::duration::*;
+
+use crate::hardware::*;
+use crate::state_machine::Direction;
+use crate::tmc::RegisterModel;
+
+pub struct MockMotor {
+    pub direction: Direction,
+    pub step_time: Microseconds,
+    pub stepping: bool,
+    pub enabled: bool,
+    pub microsteps: u8,
+    pub reversed: bool,
+    pub position: i32,
+    /// An end switch held back a step.
+    pub limit_hit: bool,
+    /// The TMC driver behind the UART.
+    pub driver: RegisterModel,
+}
+
+impl Default for MockMotor {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Forward,
+            step_time: Microseconds(0),
+            stepping: false,
+            enabled: true,
+            microsteps: 32,
+            reversed: false,
+            position: 0,
+            limit_hit: false,
+            driver: RegisterModel::default(),
+        }
+    }
+}
+
+/// A GPIO pin. A broken pin fails every access.
+#[cfg(feature = "embedded-hal")]
+pub struct MockPin {
+    pub high: bool,
+    pub broken: bool,
+}
+
+/// An input with a pull-up reads high.
+#[cfg(feature = "embedded-hal")]
+impl Default for MockPin {
+    fn default() -> Self {
+        Self {
+            high: true,
+            broken: false,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+#[derive(Debug)]
+pub struct BrokenPin;
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::digital::Error for BrokenPin {
+    fn kind(&self) -> embedded_hal::digital::ErrorKind {
+        embedded_hal::digital::ErrorKind::Other
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::digital::ErrorType for MockPin {
+    type Error = BrokenPin;
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::digital::OutputPin for MockPin {
+    fn set_low(&mut self) -> Result<(), BrokenPin> {
+        self.set_state(false)
+    }
+
+    fn set_high(&mut self) -> Result<(), BrokenPin> {
+        self.set_state(true)
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl embedded_hal::digital::InputPin for MockPin {
+    fn is_high(&mut self) -> Result<bool, BrokenPin> {
+        match self.broken {
+            false => Ok(self.high),
+            true => Err(BrokenPin),
+        }
+    }
+
+    fn is_low(&mut self) -> Result<bool, BrokenPin> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl MockPin {
+    fn set_state(&mut self, high: bool) -> Result<(), BrokenPin> {
+        match self.broken {
+            false => {
+                self.high = high;
+                Ok(())
+            }
+            true => Err(BrokenPin),
+        }
+    }
+}
+
+impl StepperOutput for MockMotor {
+    fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn set_microsteps(&mut self, microsteps: u8) {
+        self.microsteps = microsteps;
+    }
+
+    fn set_reversed(&mut self, reversed: bool) {
+        self.reversed = reversed;
+    }
+
+    fn position(&self) -> i32 {
+        self.position
+    }
+
+    fn set_position(&mut self, position: i32) {
+        self.position = position;
+    }
+
+    fn take_limit_hit(&mut self) -> bool {
+        core::mem::take(&mut self.limit_hit)
+    }
+}
+
+impl DriverUart for MockMotor {
+    fn send(&mut self, datagram: &[u8]) {
+        self.driver.send(datagram);
+    }
+
+    fn transfer(&mut self, request: &[u8], reply: &mut [u8]) -> bool {
+        self.driver.transfer(request, reply)
+    }
+}
+
+impl StepTimer for MockMotor {
+    fn set_step_time(&mut self, time: Microseconds) {
+        self.step_time = time;
+    }
+
+    fn set_stepping(&mut self, active: bool) {
+        self.stepping = active;
+    }
+}
+
+/// An erased EEPROM of the Atmega328p.
+pub struct MockStorage {
+    pub data: [u8; 1024],
+    /// The bytes that are written before the power is cut.
+    writable: Option<usize>,
+}
+
+impl MockStorage {
+    /// Drops every write after the given number of bytes, like a power failure.
+    pub fn fail_after(&mut self, bytes: usize) {
+        self.writable = Some(bytes);
+    }
+}
+
+impl Default for MockStorage {
+    fn default() -> Self {
+        Self {
+            data: [0xFF; 1024],
+            writable: None,
+        }
+    }
+}
+
+impl Storage for MockStorage {
+    fn read(&self, address: u16, buffer: &mut [u8]) {
+        let address = address as usize;
+        buffer.copy_from_slice(&self.data[address..address + buffer.len()]);
+    }
+
+    fn write(&mut self, address: u16, data: &[u8]) {
+        let address = address as usize;
+        let length = match self.writable {
+            Some(writable) => {
+                let length = data.len().min(writable);
+                self.writable = Some(writable - length);
+                length
+            }
+            None => data.len(),
+        };
+        self.data[address..address + length].copy_from_slice(&data[..length]);
+    }
+
+    fn is_busy(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct MockSerial {
+    pub input: VecDeque<Result<u8, RxError>>,
+    pub output: String,
+}
+
+impl MockSerial {
+    pub fn receive(&mut self, input: &str) {
+        self.input.extend(input.bytes().map(Ok));
+    }
+
+    pub fn receive_error(&mut self, error: RxError) {
+        self.input.push_back(Err(error));
+    }
+}
+
+impl SerialPort for MockSerial {
+    fn read_byte(&mut self) -> Option<Result<u8, RxError>> {
+        self.input.pop_front()
+    }
+
+    fn write_str(&mut self, string: &str) {
+        self.output.push_str(string);
+    }
+}
+
+#[derive(Default)]
+pub struct MockClock {
+    pub millis: u32,
+}
+
+impl Clock for MockClock {
+    fn millis(&self) -> u32 {
+        self.millis
+    }
+}
+
+#[cfg(feature = "supply")]
+#[derive(Default)]
+pub struct MockSupply {
+    pub millivolts: Option<u16>,
+}
+
+#[cfg(feature = "supply")]
+impl SupplyVoltage for MockSupply {
+    fn millivolts(&mut self) -> Option<u16> {
+        self.millivolts
+    }
+}
+
+pub struct MockHardware;
+
+impl Hardware for MockHardware {
+    type Motor = MockMotor;
+    type Storage = MockStorage;
+    type Serial = MockSerial;
+    type Clock = MockClock;
+    #[cfg(feature = "supply")]
+    type Supply = MockSupply;
+
+    #[cfg(feature = "bootloader")]
+    const BOOTLOADER_KEY: crate::auth::Key = *b"0123456789secret";
+}
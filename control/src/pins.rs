@@ -0,0 +1,155 @@
+//! The driver and the end switches on plain GPIO pins, written against the
+//! digital traits of `embedded-hal`. Any HAL that implements them can use
+//! these types, so only the step timer and the pin map are left to the
+//! firmware of a chip.
+//!
+//! The pins of the AVR can not fail. A failing pin is ignored when written,
+//! and an end switch that can not be read counts as closed, so the motor
+//! stops instead of running into the end.
+
+use embedded_hal::digital::{InputPin, OutputPin, PinState};
+
+use crate::state_machine::Direction;
+
+/// The direction, enable and microstep mode pins M0 to M2 of a DRV8825.
+pub struct DriverPins<Dir, Enable, M0, M1, M2> {
+    dir: Dir,
+    enable: Enable,
+    mode: (M0, M1, M2),
+    direction: Direction,
+    /// The direction pin is inverted for a motor mounted the other way round.
+    reversed: bool,
+}
+
+impl<Dir, Enable, M0, M1, M2> DriverPins<Dir, Enable, M0, M1, M2>
+where
+    Dir: OutputPin,
+    Enable: OutputPin,
+    M0: OutputPin,
+    M1: OutputPin,
+    M2: OutputPin,
+{
+    /// The driver starts disabled and forward.
+    pub fn new(dir: Dir, enable: Enable, mode: (M0, M1, M2)) -> Self {
+        let mut pins = Self {
+            dir,
+            enable,
+            mode,
+            direction: Direction::Forward,
+            reversed: false,
+        };
+        pins.set_enabled(false);
+        pins.write_direction();
+        pins
+    }
+
+    /// The direction of the platform, regardless of the mounting of the motor.
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+        self.write_direction();
+    }
+
+    /// The position is still counted in the direction of the platform.
+    pub fn set_reversed(&mut self, reversed: bool) {
+        self.reversed = reversed;
+        self.write_direction();
+    }
+
+    /// The enable input of the driver is active low.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enable.set_state(PinState::from(!enabled)).ok();
+    }
+
+    /// The pins M0 to M2 hold the binary logarithm of the microsteps,
+    /// e.g. 0b101 selects 1/32 steps.
+    pub fn set_microsteps(&mut self, microsteps: u8) {
+        let mode = microsteps.trailing_zeros();
+        let level = |bit: u32| PinState::from(mode & bit != 0);
+        self.mode.0.set_state(level(0b001)).ok();
+        self.mode.1.set_state(level(0b010)).ok();
+        self.mode.2.set_state(level(0b100)).ok();
+    }
+
+    fn write_direction(&mut self) {
+        let forward = self.direction == Direction::Forward;
+        self.dir
+            .set_state(PinState::from(forward != self.reversed))
+            .ok();
+    }
+}
+
+/// The end switches close to ground.
+pub struct EndSwitches<Forward, Backward> {
+    forward: Forward,
+    backward: Backward,
+}
+
+impl<Forward: InputPin, Backward: InputPin> EndSwitches<Forward, Backward> {
+    pub fn new(forward: Forward, backward: Backward) -> Self {
+        Self { forward, backward }
+    }
+
+    /// Only the switch in the direction of travel counts.
+    pub fn closed(&mut self, direction: Direction) -> bool {
+        match direction {
+            Direction::Forward => self.forward.is_low().unwrap_or(true),
+            Direction::Backward => self.backward.is_low().unwrap_or(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPin;
+
+    #[test]
+    fn driver_pins_follow_the_settings() {
+        let mut pins = DriverPins::new(
+            MockPin::default(),
+            MockPin::default(),
+            (MockPin::default(), MockPin::default(), MockPin::default()),
+        );
+        assert!(pins.enable.high && pins.dir.high);
+
+        pins.set_enabled(true);
+        assert!(!pins.enable.high);
+
+        pins.set_direction(Direction::Backward);
+        assert!(!pins.dir.high);
+        pins.set_reversed(true);
+        assert!(pins.dir.high);
+        assert_eq!(pins.direction(), Direction::Backward);
+
+        pins.set_microsteps(32);
+        assert_eq!(
+            (pins.mode.0.high, pins.mode.1.high, pins.mode.2.high),
+            (true, false, true)
+        );
+        pins.set_microsteps(4);
+        assert_eq!(
+            (pins.mode.0.high, pins.mode.1.high, pins.mode.2.high),
+            (false, true, false)
+        );
+    }
+
+    #[test]
+    fn only_the_switch_ahead_counts() {
+        let mut switches = EndSwitches::new(
+            MockPin {
+                high: false,
+                broken: false,
+            },
+            MockPin::default(),
+        );
+        assert!(switches.closed(Direction::Forward));
+        assert!(!switches.closed(Direction::Backward));
+
+        switches.backward.broken = true;
+        assert!(switches.closed(Direction::Backward));
+    }
+}
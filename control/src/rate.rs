@@ -0,0 +1,105 @@
+//! The math to derive the waiting time between two steps from the base tracking time.
+
+use embedded_time::duration::*;
+
+use crate::state_machine::GuideDirection;
+
+/// The trim is limited to +-10% of the base tracking rate. Anything
+/// beyond that is not a trim anymore and should be set as a new base time.
+pub const MAX_TRIM_PPM: i32 = 100_000;
+
+/// Guiding speeds up or slows down the tracking rate by this percentage by default.
+pub const GUIDE_RATE_PERCENT: u8 = 50;
+
+/// Slowing down by 100% or more would stop the platform.
+pub const MAX_GUIDE_RATE_PERCENT: u8 = 99;
+
+/// The longest time between two steps the step timer can count: each half of
+/// the step is at most 65535 ticks of 4 µs. Longer times are cut to it.
+pub const MAX_STEP_TIME: Microseconds = Microseconds(524_280);
+
+pub fn clamp_step_time(time: u64) -> Microseconds {
+    Microseconds(time.min(*MAX_STEP_TIME.integer() as u64) as u32)
+}
+
+pub fn clamp_trim(trim_ppm: i32) -> i32 {
+    trim_ppm.clamp(-MAX_TRIM_PPM, MAX_TRIM_PPM)
+}
+
+/// A positive trim speeds up the tracking rate, so the time
+/// between two steps gets shorter and vice versa.
+pub fn apply_trim(waiting_time: Microseconds, trim_ppm: i32) -> Microseconds {
+    let time = *waiting_time.integer() as u64;
+    let divisor = (1_000_000 + clamp_trim(trim_ppm) as i64) as u64;
+
+    clamp_step_time(time * 1_000_000 / divisor)
+}
+
+/// Guiding faster means a shorter time between two steps and vice versa.
+pub fn apply_guide_rate(
+    tracking_time: Microseconds,
+    direction: GuideDirection,
+    rate_percent: u8,
+) -> Microseconds {
+    let time = *tracking_time.integer() as u64;
+    let rate = rate_percent.min(MAX_GUIDE_RATE_PERCENT) as u64;
+
+    let time = match direction {
+        GuideDirection::Faster => time * 100 / (100 + rate),
+        GuideDirection::Slower => time * 100 / (100 - rate),
+    };
+    clamp_step_time(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_changes_the_time_by_parts_per_million() {
+        assert_eq!(
+            apply_trim(Microseconds(100_000), 0),
+            Microseconds(100_000_u32)
+        );
+        assert_eq!(
+            apply_trim(Microseconds(100_000), 1_000),
+            Microseconds(99_900_u32)
+        );
+        assert_eq!(
+            apply_trim(Microseconds(100_000), -1_000),
+            Microseconds(100_100_u32)
+        );
+    }
+
+    #[test]
+    fn trim_is_clamped() {
+        assert_eq!(clamp_trim(i32::MAX), MAX_TRIM_PPM);
+        assert_eq!(clamp_trim(i32::MIN), -MAX_TRIM_PPM);
+    }
+
+    #[test]
+    fn guide_rate() {
+        assert_eq!(
+            apply_guide_rate(Microseconds(30_000), GuideDirection::Faster, 50),
+            Microseconds(20_000_u32)
+        );
+        assert_eq!(
+            apply_guide_rate(Microseconds(30_000), GuideDirection::Slower, 50),
+            Microseconds(60_000_u32)
+        );
+        assert_eq!(
+            apply_guide_rate(Microseconds(3_000), GuideDirection::Slower, 100),
+            Microseconds(300_000_u32)
+        );
+    }
+
+    #[test]
+    fn step_time_is_capped_at_the_timer_limit() {
+        assert_eq!(
+            apply_guide_rate(Microseconds(30_000), GuideDirection::Slower, 99),
+            MAX_STEP_TIME
+        );
+        assert_eq!(apply_trim(MAX_STEP_TIME, -MAX_TRIM_PPM), MAX_STEP_TIME);
+        assert_eq!(clamp_step_time(u64::MAX), MAX_STEP_TIME);
+    }
+}
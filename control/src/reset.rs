@@ -0,0 +1,173 @@
+//! Why the chip restarted. The firmware reads the reset flags of the chip at
+//! boot, the controller counts every cause in a wear-levelled log. A reset
+//! requested with the "r" command is a watchdog reset as well, so the request
+//! is stored before the watchdog is starved and tells the two apart. The same
+//! goes for the bootloader, which starts the firmware without a reset flag.
+
+use crate::wear_log::{Record, Region};
+
+/// The unused space in front of the legacy settings.
+pub const RESET_REGION: Region = Region {
+    base: 0x0010,
+    entries: 10,
+};
+
+/// The number of counters stored, with room for causes added later.
+const COUNTER_SLOTS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ResetCause {
+    PowerOn,
+    /// The reset pin was pulled, e.g. by the DTR line of the serial adapter.
+    External,
+    /// The supply voltage dropped below the brown-out level.
+    BrownOut,
+    /// The main loop hung and the watchdog fired.
+    Watchdog,
+    /// The user asked for the reset.
+    Command,
+    /// No flag was set, e.g. after a jump to the reset vector.
+    Unknown,
+    /// The firmware panicked and reset itself.
+    Panic,
+}
+
+impl ResetCause {
+    pub const ALL: [ResetCause; 7] = [
+        ResetCause::PowerOn,
+        ResetCause::External,
+        ResetCause::BrownOut,
+        ResetCause::Watchdog,
+        ResetCause::Command,
+        ResetCause::Unknown,
+        ResetCause::Panic,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetCause::PowerOn => "power-on",
+            ResetCause::External => "external",
+            ResetCause::BrownOut => "brown-out",
+            ResetCause::Watchdog => "watchdog",
+            ResetCause::Command => "command",
+            ResetCause::Unknown => "unknown",
+            ResetCause::Panic => "panic",
+        }
+    }
+
+    /// The position of the counter in the log. It must never change.
+    fn index(&self) -> usize {
+        match self {
+            ResetCause::PowerOn => 0,
+            ResetCause::External => 1,
+            ResetCause::BrownOut => 2,
+            ResetCause::Watchdog => 3,
+            ResetCause::Command => 4,
+            ResetCause::Unknown => 5,
+            ResetCause::Panic => 6,
+        }
+    }
+
+    fn from_index(index: u8) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|cause| cause.index() == index as usize)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Resets {
+    counts: [u16; COUNTER_SLOTS],
+    pub last: Option<ResetCause>,
+    /// A reset was requested, the next watchdog reset is no failure.
+    pub requested: bool,
+}
+
+impl Resets {
+    /// Counts the reset at boot. A watchdog reset or a return from the
+    /// bootloader after a request was asked for.
+    pub fn count(&mut self, cause: ResetCause) -> ResetCause {
+        let cause = match cause {
+            ResetCause::Watchdog | ResetCause::Unknown if self.requested => ResetCause::Command,
+            cause => cause,
+        };
+
+        let count = &mut self.counts[cause.index()];
+        *count = count.saturating_add(1);
+        self.last = Some(cause);
+        self.requested = false;
+        cause
+    }
+
+    pub fn get(&self, cause: ResetCause) -> u16 {
+        self.counts[cause.index()]
+    }
+}
+
+impl Record for Resets {
+    const LENGTH: usize = 2 * COUNTER_SLOTS + 2;
+
+    fn encode(&self, data: &mut [u8]) {
+        for (bytes, count) in data.chunks_mut(2).zip(self.counts.iter()) {
+            bytes.copy_from_slice(&count.to_be_bytes());
+        }
+        data[2 * COUNTER_SLOTS] = self.last.map_or(0xFF, |cause| cause.index() as u8);
+        data[2 * COUNTER_SLOTS + 1] = self.requested as u8;
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        let mut counts = [0_u16; COUNTER_SLOTS];
+        for (count, bytes) in counts.iter_mut().zip(data.chunks(2)) {
+            *count = u16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+
+        Self {
+            counts,
+            last: ResetCause::from_index(data[2 * COUNTER_SLOTS]),
+            requested: data[2 * COUNTER_SLOTS + 1] != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockStorage;
+    use crate::wear_log::WearLog;
+
+    #[test]
+    fn requested_watchdog_reset_is_a_command() {
+        let mut resets = Resets::default();
+        assert_eq!(resets.count(ResetCause::Watchdog), ResetCause::Watchdog);
+
+        resets.requested = true;
+        assert_eq!(resets.count(ResetCause::Watchdog), ResetCause::Command);
+        resets.requested = true;
+        assert_eq!(resets.count(ResetCause::Unknown), ResetCause::Command);
+        assert_eq!(resets.count(ResetCause::BrownOut), ResetCause::BrownOut);
+
+        assert_eq!(resets.get(ResetCause::Watchdog), 1);
+        assert_eq!(resets.get(ResetCause::Command), 2);
+        assert_eq!(resets.last, Some(ResetCause::BrownOut));
+        assert!(!resets.requested);
+    }
+
+    #[test]
+    fn counters_are_kept_in_the_log() {
+        let mut storage = MockStorage::default();
+        let (mut log, loaded) = WearLog::<Resets>::load(RESET_REGION, &storage);
+        assert_eq!(loaded, None);
+
+        let mut resets = Resets::default();
+        for _ in 0..RESET_REGION.entries + 2 {
+            resets.count(ResetCause::PowerOn);
+            log.write(&resets, &mut storage);
+        }
+        resets.requested = true;
+        log.write(&resets, &mut storage);
+
+        let (_, loaded) = WearLog::<Resets>::load(RESET_REGION, &storage);
+        assert_eq!(loaded, Some(resets));
+    }
+}
@@ -0,0 +1,136 @@
+//! A queue between one interrupt and the main loop, e.g. for the received
+//! bytes. The interrupt is the only producer, the main loop the only consumer,
+//! so no critical section is needed: each side only writes its own index and
+//! reads the other one. The AVR has no compare-and-swap, but atomic loads and
+//! stores of a byte.
+//!
+//! The indices run freely and wrap at 256, the capacity is a power of two
+//! up to 128, so the difference of the indices is the number of values.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub struct RingBuffer<T, const N: usize> {
+    slots: UnsafeCell<[MaybeUninit<T>; N]>,
+    /// Only written by the producer.
+    head: AtomicU8,
+    /// Only written by the consumer.
+    tail: AtomicU8,
+}
+
+// SAFETY:
+// A slot is written by the producer before the head is moved past it and read
+// by the consumer before the tail is moved past it. The orderings of the index
+// accesses keep both sides apart, as long as there is only one of each.
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two() && N <= 128);
+        Self {
+            slots: UnsafeCell::new([MaybeUninit::uninit(); N]),
+            head: AtomicU8::new(0),
+            tail: AtomicU8::new(0),
+        }
+    }
+
+    /// Only for the producer. Returns false when the buffer is full,
+    /// the value is dropped then.
+    pub fn push(&self, value: T) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) as usize == N {
+            return false;
+        }
+
+        // SAFETY:
+        // The slot is not visible to the consumer until the head is moved.
+        unsafe { (*self.slots.get())[head as usize % N] = MaybeUninit::new(value) };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Only for the consumer.
+    pub fn pop(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // SAFETY:
+        // The producer has written the slot before it moved the head past it
+        // and does not touch it again until the tail is moved.
+        let value = unsafe { (*self.slots.get())[tail as usize % N].assume_init() };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        head.wrapping_sub(tail) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_come_out_in_order_across_the_wrap() {
+        let buffer = RingBuffer::<u8, 4>::new();
+        for round in 0..100_u8 {
+            assert!(buffer.push(round));
+            assert!(buffer.push(round.wrapping_mul(3)));
+            assert_eq!(buffer.len(), 2);
+            assert_eq!(buffer.pop(), Some(round));
+            assert_eq!(buffer.pop(), Some(round.wrapping_mul(3)));
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn a_full_buffer_drops_new_bytes() {
+        let buffer = RingBuffer::<u8, 128>::new();
+        for byte in 0..128 {
+            assert!(buffer.push(byte));
+        }
+        assert!(!buffer.push(200));
+        assert_eq!(buffer.len(), 128);
+        assert_eq!(buffer.pop(), Some(0));
+        assert!(buffer.push(201));
+    }
+
+    #[test]
+    fn producer_and_consumer_run_concurrently() {
+        static BUFFER: RingBuffer<u8, 16> = RingBuffer::new();
+        let producer = std::thread::spawn(|| {
+            for byte in 0..=255_u8 {
+                while !BUFFER.push(byte) {}
+            }
+        });
+
+        for expected in 0..=255_u8 {
+            let byte = loop {
+                if let Some(byte) = BUFFER.pop() {
+                    break byte;
+                }
+            };
+            assert_eq!(byte, expected);
+        }
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,1145 @@
+//! The serial command interface. The received bytes are assembled to lines
+//! which are parsed into commands. All the replies are formatted here as well.
+
+use core::convert::Infallible;
+
+use embedded_time::duration::*;
+
+use crate::config::MAX_MICROSTEPS;
+#[cfg(feature = "profiles")]
+use crate::config::{ProfileName, PROFILE_NAME_LENGTH};
+use crate::eeprom::PanicRecord;
+#[cfg(feature = "profiles")]
+use crate::eeprom::PROFILE_SLOTS;
+use crate::hardware::{RxError, SerialPort};
+use crate::history::{Cause, Change, History, Setting};
+use crate::link::LinkLoss;
+#[cfg(feature = "guiding")]
+use crate::rate::MAX_GUIDE_RATE_PERCENT;
+use crate::reset::{ResetCause, Resets};
+use crate::startup::PowerOn;
+use crate::state_machine::Direction;
+#[cfg(feature = "guiding")]
+use crate::state_machine::GuideDirection;
+#[cfg(feature = "tmc")]
+use crate::tmc::{Chopper, DriverStatus, MAX_CURRENT_MA};
+
+/// The trim step that is used when no explicit value is given.
+const TRIM_STEP_PPM: i32 = 10;
+
+/// The maximum length of a command line. Longer lines are truncated.
+const LINE_LENGTH: usize = 64;
+
+/// The bytes of an exported blob per line. Their hex digits
+/// and the command in front still fit into one line.
+#[cfg(feature = "backup")]
+pub const CHUNK_LENGTH: usize = 24;
+
+/// A part of an imported blob.
+#[cfg(feature = "backup")]
+pub struct Chunk {
+    bytes: [u8; CHUNK_LENGTH],
+    length: usize,
+}
+
+#[cfg(feature = "backup")]
+impl Chunk {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+pub enum InputVariant {
+    Track,
+    TrackNewTime(Microseconds),
+    SetTrim(i32),
+    AdjustTrim(i32),
+    /// The travel limit in steps, `None` removes the limit.
+    SetTravelLimit(Option<i32>),
+    SetParkPosition(i32),
+    SetMicrosteps(u8),
+    SetReversed(bool),
+    #[cfg(feature = "guiding")]
+    SetGuideRate(u8),
+    /// The seconds the host may be silent while slewing or guiding, zero disables the watch.
+    SetLinkTimeout(u16),
+    SetLinkLoss(LinkLoss),
+    /// The host is still there.
+    Heartbeat,
+    /// The supply voltage in millivolts to warn at, `None` disables the warning.
+    #[cfg(feature = "supply")]
+    SetSupplyWarning(Option<u16>),
+    /// The supply voltage in millivolts to park at, `None` disables the cutoff.
+    #[cfg(feature = "supply")]
+    SetSupplyCutoff(Option<u16>),
+    /// Reads the flags of the TMC driver.
+    #[cfg(feature = "tmc")]
+    DriverStatus,
+    /// The currents of the TMC driver in mA.
+    #[cfg(feature = "tmc")]
+    SetRunCurrent(u16),
+    #[cfg(feature = "tmc")]
+    SetHoldCurrent(u16),
+    #[cfg(feature = "tmc")]
+    SetChopper(Chopper),
+    #[cfg(feature = "tmc")]
+    SetInterpolation(bool),
+    #[cfg(feature = "profiles")]
+    ListProfiles,
+    /// Saves the current settings to the slot.
+    #[cfg(feature = "profiles")]
+    SaveProfile(u8, ProfileName),
+    #[cfg(feature = "profiles")]
+    LoadProfile(u8),
+    /// The profile that is loaded on boot, `None` boots with the current settings.
+    #[cfg(feature = "profiles")]
+    SetBootProfile(Option<u8>),
+    /// Prints all settings as a blob that can be imported again.
+    #[cfg(feature = "backup")]
+    Export,
+    /// Starts receiving a blob.
+    #[cfg(feature = "backup")]
+    ImportBegin,
+    #[cfg(feature = "backup")]
+    ImportData(Chunk),
+    /// Checks the received blob and writes it to the EEPROM.
+    #[cfg(feature = "backup")]
+    ImportCommit,
+    SetPowerOn(PowerOn),
+    /// Starts tracking after the given minutes.
+    ArmCountdown(u16),
+    CancelCountdown,
+    Hold,
+    FastForward(Direction),
+    #[cfg(feature = "guiding")]
+    Guide(GuideDirection),
+    #[cfg(feature = "guiding")]
+    GuideDone,
+    Rewind,
+    Home,
+    Park,
+    Unpark,
+    ClearFault,
+    SetDefault,
+    Status,
+    BinaryStatus,
+    History,
+    Reset,
+    /// Asks for a challenge the bootloader command has to answer.
+    #[cfg(feature = "bootloader")]
+    BootloaderChallenge,
+    /// Starts the bootloader if the response matches the challenge.
+    #[cfg(feature = "bootloader")]
+    Bootloader(u64),
+    Invalid,
+}
+
+/// The values shown by the status commands.
+pub struct Status<'a> {
+    pub state: &'a str,
+    pub current_time: u32,
+    pub default_time: u32,
+    pub trim: i32,
+    pub position: i32,
+    pub starts: u32,
+    /// The total time spent tracking.
+    pub usage_minutes: u32,
+    pub uptime_seconds: u32,
+    /// The time spent tracking since the platform was last at the start of its travel.
+    pub session_seconds: u32,
+    pub travel_limit: Option<i32>,
+    pub park_position: i32,
+    /// The tracking time left until the travel limit is reached.
+    pub remaining_seconds: Option<u32>,
+    pub power_on: &'a str,
+    /// The time until the countdown starts tracking, if it is armed.
+    pub start_in_seconds: Option<u32>,
+    /// Where the settings came from.
+    pub config: &'a str,
+    /// `None` if the link is not watched.
+    pub link_timeout_seconds: Option<u16>,
+    pub link_loss: &'a str,
+    /// The smoothed supply voltage, `None` until the first reading.
+    #[cfg(feature = "supply")]
+    pub supply_millivolts: Option<u16>,
+    #[cfg(feature = "supply")]
+    pub supply_level: &'a str,
+    #[cfg(feature = "supply")]
+    pub supply_warning_mv: Option<u16>,
+    #[cfg(feature = "supply")]
+    pub supply_cutoff_mv: Option<u16>,
+    #[cfg(feature = "tmc")]
+    pub run_current_ma: u16,
+    #[cfg(feature = "tmc")]
+    pub hold_current_ma: u16,
+    #[cfg(feature = "tmc")]
+    pub chopper: &'a str,
+    pub last_reset: &'a str,
+    pub resets: Resets,
+    pub last_panic: Option<PanicRecord>,
+    pub rx_errors: RxErrors,
+}
+
+/// Shows a missing value as "-".
+struct Optional<T>(Option<T>);
+
+impl<T: ufmt::uDisplay> ufmt::uDisplay for Optional<T> {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        match self.0 {
+            Some(ref value) => value.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// Shows the location as "file:line".
+impl ufmt::uDisplay for PanicRecord {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        ufmt::uwrite!(f, "{}:{}", self.file(), self.line)
+    }
+}
+
+/// Lets ufmt write into the serial port.
+struct Writer<S>(S);
+
+impl<S: SerialPort> ufmt::uWrite for Writer<S> {
+    type Error = Infallible;
+
+    fn write_str(&mut self, string: &str) -> Result<(), Infallible> {
+        self.0.write_str(string);
+        Ok(())
+    }
+}
+
+/// The receive errors since startup, to tell a flaky link, e.g. a Bluetooth
+/// module, from a wrong baud rate.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct RxErrors {
+    counts: [u16; 4],
+}
+
+impl RxErrors {
+    pub fn count(&mut self, error: RxError) {
+        let count = &mut self.counts[error as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn get(&self, error: RxError) -> u16 {
+        self.counts[error as usize]
+    }
+}
+
+pub struct SerialHandler<S> {
+    port: Writer<S>,
+    line: [u8; LINE_LENGTH],
+    /// The bytes received for the line, also those that did not fit.
+    length: usize,
+    /// The first error in the line, the whole line is dropped at its end.
+    line_error: Option<RxError>,
+    rx_errors: RxErrors,
+}
+
+impl<S: SerialPort> SerialHandler<S> {
+    pub fn new(port: S) -> Self {
+        Self {
+            port: Writer(port),
+            line: [0; LINE_LENGTH],
+            length: 0,
+            line_error: None,
+            rx_errors: RxErrors::default(),
+        }
+    }
+
+    /// A line with a receive error is not parsed, it could be any command.
+    /// It is answered with the error instead.
+    pub fn handle_input(&mut self) -> Option<InputVariant> {
+        while let Some(received) = self.port.0.read_byte() {
+            let byte = match received {
+                Ok(byte) => byte,
+                Err(error) => {
+                    self.reject_line(error);
+                    continue;
+                }
+            };
+
+            if byte == b'\n' {
+                let length = self.length;
+                self.length = 0;
+
+                if let Some(error) = self.line_error.take() {
+                    ufmt::uwriteln!(self.port, "Line dropped: {}!", error.name()).ok();
+                    continue;
+                }
+
+                let input = core::str::from_utf8(&self.line[..length]).unwrap_or("");
+                ufmt::uwriteln!(self.port, "Got: {}", input).ok();
+                return Some(parse_input(input));
+            }
+
+            match self.length {
+                length if length < LINE_LENGTH => self.line[length] = byte,
+                // The first byte that does not fit counts, the rest is ignored.
+                LINE_LENGTH => self.reject_line(RxError::Overflow),
+                _ => (),
+            }
+            self.length = self.length.saturating_add(1);
+        }
+        None
+    }
+
+    fn reject_line(&mut self, error: RxError) {
+        self.rx_errors.count(error);
+        if self.line_error.is_none() {
+            self.line_error = Some(error);
+        }
+    }
+
+    pub fn rx_errors(&self) -> RxErrors {
+        self.rx_errors
+    }
+
+    #[cfg(test)]
+    pub(crate) fn port(&mut self) -> &mut S {
+        &mut self.port.0
+    }
+
+    pub fn write_str(&mut self, string: &str) {
+        self.port.0.write_str(string);
+    }
+
+    pub fn write_number<T: ufmt::uDisplay>(&mut self, value: T) {
+        ufmt::uwrite!(self.port, "{}", value).ok();
+    }
+
+    /// All 16 digits, like the bootloader challenges are given.
+    #[cfg(feature = "bootloader")]
+    pub fn write_hex(&mut self, value: u64) {
+        let mut hex = [0_u8; 16];
+        for (index, digit) in hex.iter_mut().enumerate() {
+            *digit = HEX_DIGITS[(value >> (60 - 4 * index) & 0x0F) as usize];
+        }
+        self.write_str(core::str::from_utf8(&hex).unwrap_or(""));
+    }
+
+    /// The lines of the optional subsystems are only there if they are compiled in.
+    pub fn send_status(&mut self, status: &Status) {
+        ufmt::uwriteln!(
+            self.port,
+            "\n\n\
+            ~~~~~~~~~~ EQPlatform-PulseGuiding ~~~~~~~~~~\n\
+            ~                                           ~\n\
+            ~          Firmware-Version: {}          ~\n\
+            ~           State: {}                    ~\n\
+            ~           Current Velocity: {}         ~\n\
+            ~           Default Velocity: {}         ~\n\
+            ~           Rate Trim (ppm): {}             ~\n\
+            ~           Position: {}                    ~\n\
+            ~           Number of starts: {}            ~\n\
+            ~           Usage (min): {}                 ~\n\
+            ~           Uptime (s): {}                  ~\n\
+            ~           Session (s): {}                 ~\n\
+            ~           Travel limit: {}                ~\n\
+            ~           Remaining (s): {}               ~\n\
+            ~           Park position: {}               ~\n\
+            ~           Power-on: {}                    ~\n\
+            ~           Start in (s): {}                ~\n\
+            ~           Config: {}                      ~\n\
+            ~           Link timeout (s): {} on loss: {} ~",
+            env!("CARGO_PKG_VERSION"),
+            status.state,
+            status.current_time,
+            status.default_time,
+            status.trim,
+            status.position,
+            status.starts,
+            status.usage_minutes,
+            status.uptime_seconds,
+            status.session_seconds,
+            Optional(status.travel_limit),
+            Optional(status.remaining_seconds),
+            status.park_position,
+            status.power_on,
+            Optional(status.start_in_seconds),
+            status.config,
+            Optional(status.link_timeout_seconds),
+            status.link_loss,
+        )
+        .ok();
+
+        #[cfg(feature = "supply")]
+        ufmt::uwriteln!(
+            self.port,
+            "\
+            ~           Supply (mV): {} {}               ~\n\
+            ~           Supply warning/cutoff (mV): {} {} ~",
+            Optional(status.supply_millivolts),
+            status.supply_level,
+            Optional(status.supply_warning_mv),
+            Optional(status.supply_cutoff_mv),
+        )
+        .ok();
+
+        #[cfg(feature = "tmc")]
+        ufmt::uwriteln!(
+            self.port,
+            "~           Driver (mA): run {} hold {} {} ~",
+            status.run_current_ma,
+            status.hold_current_ma,
+            status.chopper,
+        )
+        .ok();
+
+        ufmt::uwriteln!(
+            self.port,
+            "\
+            ~           Last reset: {}                  ~\n\
+            ~           Resets: pwr {} ext {} bod {} wdt {} cmd {} ? {} panic {} ~\n\
+            ~           Last panic: {}                  ~\n\
+            ~           RX errors: ovf {} frm {} par {} ovr {} ~",
+            status.last_reset,
+            status.resets.get(ResetCause::PowerOn),
+            status.resets.get(ResetCause::External),
+            status.resets.get(ResetCause::BrownOut),
+            status.resets.get(ResetCause::Watchdog),
+            status.resets.get(ResetCause::Command),
+            status.resets.get(ResetCause::Unknown),
+            status.resets.get(ResetCause::Panic),
+            Optional(status.last_panic),
+            status.rx_errors.get(RxError::Overflow),
+            status.rx_errors.get(RxError::Framing),
+            status.rx_errors.get(RxError::Parity),
+            status.rx_errors.get(RxError::Overrun),
+        )
+        .ok();
+
+        self.write_str("~           Features:");
+        for feature in crate::features() {
+            self.write_str(" ");
+            self.write_str(feature);
+        }
+        self.write_str(
+            "  ~\n\
+            ~                                           ~\n\
+            ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~\n",
+        );
+    }
+
+    pub fn send_binary_status(&mut self, status: &Status) {
+        ufmt::uwriteln!(
+            self.port,
+            "{}\n{}\n{}\n{}\n",
+            status.current_time,
+            status.default_time,
+            status.starts,
+            status.trim,
+        )
+        .ok();
+    }
+
+    /// Prints the flags of the TMC driver, the problems first.
+    #[cfg(feature = "tmc")]
+    pub fn send_driver_status(&mut self, status: &DriverStatus) {
+        self.write_str("Driver:");
+        let flags = [
+            (status.overtemperature, " overtemperature!"),
+            (status.temperature_warning, " hot!"),
+            (status.short, " short!"),
+            (status.open_load, " open load!"),
+            (status.stealth, " stealth"),
+            (status.standstill, " standstill"),
+        ];
+        for (_, name) in flags.iter().filter(|(set, _)| *set) {
+            self.write_str(name);
+        }
+        ufmt::uwriteln!(self.port, " CS {}", status.current_scale).ok();
+    }
+
+    /// Prints the blob as import commands, so the output can be sent back line by line:
+    /// "i+" starts the import, every "i:" line carries a chunk in hex and "i=" writes it.
+    /// The export passes the blob in pieces, only one chunk is kept at a time.
+    #[cfg(feature = "backup")]
+    pub fn send_export(&mut self, export: impl FnOnce(&mut dyn FnMut(&[u8]))) {
+        self.write_str("i+\n");
+        let mut chunk = Chunk {
+            bytes: [0; CHUNK_LENGTH],
+            length: 0,
+        };
+        export(&mut |bytes| {
+            for byte in bytes {
+                chunk.bytes[chunk.length] = *byte;
+                chunk.length += 1;
+                if chunk.length == CHUNK_LENGTH {
+                    self.send_chunk(chunk.as_bytes());
+                    chunk.length = 0;
+                }
+            }
+        });
+        if chunk.length > 0 {
+            self.send_chunk(chunk.as_bytes());
+        }
+        self.write_str("i=\n");
+    }
+
+    #[cfg(feature = "backup")]
+    fn send_chunk(&mut self, chunk: &[u8]) {
+        let mut hex = [0_u8; 2 * CHUNK_LENGTH];
+        for (digits, byte) in hex.chunks_mut(2).zip(chunk) {
+            digits[0] = HEX_DIGITS[(byte >> 4) as usize];
+            digits[1] = HEX_DIGITS[(byte & 0x0F) as usize];
+        }
+        self.write_str("i:");
+        self.write_str(core::str::from_utf8(&hex[..2 * chunk.len()]).unwrap_or(""));
+        self.write_str("\n");
+    }
+
+    /// Prints one line per entry, the oldest one first. The time is the uptime in ms.
+    pub fn send_history(&mut self, history: &History) {
+        self.write_str("History:\n");
+        for entry in history.iter() {
+            ufmt::uwrite!(self.port, "{} ", entry.time).ok();
+            let refused = match entry.change {
+                Change::Transition {
+                    from,
+                    to,
+                    event,
+                    refused,
+                } => {
+                    ufmt::uwrite!(
+                        self.port,
+                        "{} -> {} ({}, ",
+                        from.name(),
+                        to.name(),
+                        event.name()
+                    )
+                    .ok();
+                    refused
+                }
+                Change::Setting(setting) => {
+                    self.write_setting(&setting);
+                    self.write_str(" (");
+                    false
+                }
+            };
+            self.write_str(entry.cause.name());
+            if let Cause::Boot(reset) = entry.cause {
+                self.write_str(" after ");
+                self.write_str(reset.name());
+            }
+            self.write_str(if refused { ") refused\n" } else { ")\n" });
+        }
+    }
+
+    fn write_setting(&mut self, setting: &Setting) {
+        self.write_str(setting.name());
+        match *setting {
+            Setting::TrackingTime(time) => ufmt::uwrite!(self.port, " = {}us", time).ok(),
+            Setting::TrimPpm(ppm) => ufmt::uwrite!(self.port, " = {}ppm", ppm).ok(),
+            Setting::TravelLimit(Some(steps)) | Setting::ParkPosition(steps) => {
+                ufmt::uwrite!(self.port, " = {} steps", steps).ok()
+            }
+            Setting::TravelLimit(None) => ufmt::uwrite!(self.port, " = none").ok(),
+            #[cfg(feature = "guiding")]
+            Setting::GuideRatePercent(percent) => ufmt::uwrite!(self.port, " = {}%", percent).ok(),
+            #[cfg(feature = "profiles")]
+            Setting::Profile(slot) => ufmt::uwrite!(self.port, " {}", slot).ok(),
+            #[cfg(feature = "backup")]
+            Setting::Import => None,
+        };
+    }
+}
+
+fn parse_input(input: &str) -> InputVariant {
+    /*  This is a mess:
+    I really don't know why but matching against the &str type like
+    'Err("track")' did not work in all cases. For example "hold" was
+    working, but "track" not. Also passing "track" directly into the
+    parse_input() function did work as expected. However creating a
+    StaticString from "track" and then giving back the &str type did
+    not work. Only checking the first char is a workaround. */
+
+    match input.parse::<u32>().map_err(|_| input.chars().next()) {
+        // First lets see if our string is a number. Then the
+        // user meant to send a new velocity to the tracker.
+        Ok(duration) => InputVariant::TrackNewTime(Microseconds(duration)),
+
+        // The "p" command trims the tracking rate in parts-per-million.
+        Err(Some('p')) => parse_trim(&input[1..]),
+
+        // "m=N" limits the travel to N steps, a plain "m" removes the limit.
+        Err(Some('m')) => match input[1..].trim() {
+            "" => InputVariant::SetTravelLimit(None),
+            argument => match argument.strip_prefix('=').map(str::parse::<i32>) {
+                Some(Ok(limit)) if limit >= 0 => InputVariant::SetTravelLimit(Some(limit)),
+                _ => InputVariant::Invalid,
+            },
+        },
+
+        // The behaviour after power-up: "n=t" tracks, "n=h" holds
+        // and "n=N" starts tracking N minutes after power-up.
+        Err(Some('n')) => match input[1..].trim() {
+            "=t" => InputVariant::SetPowerOn(PowerOn::Track),
+            "=h" => InputVariant::SetPowerOn(PowerOn::Hold),
+            argument => match argument.strip_prefix('=').map(str::parse::<u16>) {
+                Some(Ok(minutes)) => InputVariant::SetPowerOn(PowerOn::Delayed(minutes)),
+                _ => InputVariant::Invalid,
+            },
+        },
+
+        // "a=N" starts tracking in N minutes, a plain "a" cancels the countdown.
+        Err(Some('a')) => match input[1..].trim() {
+            "" => InputVariant::CancelCountdown,
+            argument => match argument.strip_prefix('=').map(str::parse::<u16>) {
+                Some(Ok(minutes)) => InputVariant::ArmCountdown(minutes),
+                _ => InputVariant::Invalid,
+            },
+        },
+
+        // Driver settings: "x=N" selects N microsteps, "v=1" reverses
+        // the motor direction and "j=N" sets the guide rate to N percent.
+        Err(Some('x')) => match input[1..].trim().strip_prefix('=').map(str::parse::<u8>) {
+            Some(Ok(microsteps))
+                if microsteps.is_power_of_two() && microsteps <= MAX_MICROSTEPS =>
+            {
+                InputVariant::SetMicrosteps(microsteps)
+            }
+            _ => InputVariant::Invalid,
+        },
+        Err(Some('v')) => match input[1..].trim() {
+            "=0" => InputVariant::SetReversed(false),
+            "=1" => InputVariant::SetReversed(true),
+            _ => InputVariant::Invalid,
+        },
+        #[cfg(feature = "guiding")]
+        Err(Some('j')) => match input[1..].trim().strip_prefix('=').map(str::parse::<u8>) {
+            Some(Ok(rate)) if (1..=MAX_GUIDE_RATE_PERCENT).contains(&rate) => {
+                InputVariant::SetGuideRate(rate)
+            }
+            _ => InputVariant::Invalid,
+        },
+
+        // "e" exports all settings, the "i" commands import them again.
+        #[cfg(feature = "backup")]
+        Err(Some('e')) => InputVariant::Export,
+        #[cfg(feature = "backup")]
+        Err(Some('i')) => match input[1..].trim() {
+            "+" => InputVariant::ImportBegin,
+            "=" => InputVariant::ImportCommit,
+            argument => match argument.strip_prefix(':').and_then(parse_chunk) {
+                Some(chunk) => InputVariant::ImportData(chunk),
+                None => InputVariant::Invalid,
+            },
+        },
+
+        // "q" tells that the host is still there. "q=N" sets the seconds the host
+        // may be silent while slewing or guiding, "q=t" and "q=h" select whether
+        // the platform tracks or holds when the host is lost.
+        Err(Some('q')) => match input[1..].trim() {
+            "" => InputVariant::Heartbeat,
+            "=t" => InputVariant::SetLinkLoss(LinkLoss::Track),
+            "=h" => InputVariant::SetLinkLoss(LinkLoss::Hold),
+            argument => match argument.strip_prefix('=').map(str::parse::<u16>) {
+                Some(Ok(seconds)) => InputVariant::SetLinkTimeout(seconds),
+                _ => InputVariant::Invalid,
+            },
+        },
+
+        // The supply thresholds in millivolts: "y=N" warns at N, "z=N" parks at N.
+        // A plain "y" or "z" disables the threshold.
+        #[cfg(feature = "supply")]
+        Err(Some('y')) => match parse_threshold(&input[1..]) {
+            Some(millivolts) => InputVariant::SetSupplyWarning(millivolts),
+            None => InputVariant::Invalid,
+        },
+        #[cfg(feature = "supply")]
+        Err(Some('z')) => match parse_threshold(&input[1..]) {
+            Some(millivolts) => InputVariant::SetSupplyCutoff(millivolts),
+            None => InputVariant::Invalid,
+        },
+
+        // The "f" commands manage the profiles.
+        #[cfg(feature = "profiles")]
+        Err(Some('f')) => parse_profile(&input[1..]),
+
+        // Alternatively the user can send a "t" to resume tracking.
+        // The commands of the TMC driver start with "tmc".
+        #[cfg(feature = "tmc")]
+        Err(Some('t')) => match input.trim().strip_prefix("tmc") {
+            Some(argument) => parse_tmc(argument.trim()),
+            None => InputVariant::Track,
+        },
+        #[cfg(not(feature = "tmc"))]
+        Err(Some('t')) => InputVariant::Track,
+
+        // If the input is "+" or "-" the user wants to enter the
+        // fast forward mode. The direction is set accordingly.
+        Err(Some('+')) => InputVariant::FastForward(Direction::Forward),
+        Err(Some('-')) => InputVariant::FastForward(Direction::Backward),
+
+        // Guide pulses: "g+" speeds up and "g-" slows down the
+        // tracking rate until the pulse is ended by a plain "g".
+        #[cfg(feature = "guiding")]
+        Err(Some('g')) => match input[1..].trim() {
+            "+" => InputVariant::Guide(GuideDirection::Faster),
+            "-" => InputVariant::Guide(GuideDirection::Slower),
+            "" => InputVariant::GuideDone,
+            _ => InputVariant::Invalid,
+        },
+
+        // Drive back to the start of the travel. With "w" the
+        // tracking continues there, with "o" the platform stays there.
+        Err(Some('w')) => InputVariant::Rewind,
+        Err(Some('o')) => InputVariant::Home,
+
+        // Park and unpark the platform. "k=N" sets the park position to N steps.
+        Err(Some('k')) => match input[1..].trim() {
+            "" => InputVariant::Park,
+            argument => match argument.strip_prefix('=').map(str::parse::<i32>) {
+                Some(Ok(position)) if position >= 0 => InputVariant::SetParkPosition(position),
+                _ => InputVariant::Invalid,
+            },
+        },
+        Err(Some('u')) => InputVariant::Unpark,
+
+        // A fault has to be cleared explicitly with "c".
+        Err(Some('c')) => InputVariant::ClearFault,
+
+        // When the input string is "h" the user wants
+        // the tracker to immediately halt.
+        Err(Some('h')) => InputVariant::Hold,
+
+        // When the user has found a good velocity he
+        // can use the "d" command to save the current
+        // velocity to the EEPROM.
+        Err(Some('d')) => InputVariant::SetDefault,
+
+        // Print some status info.
+        Err(Some('s')) => InputVariant::Status,
+
+        // Print some binary status info. The firmware update is started
+        // with "bootloader", followed by "bootloader=N" with the response.
+        #[cfg(feature = "bootloader")]
+        Err(Some('b')) => match input.trim().strip_prefix("bootloader") {
+            Some("") => InputVariant::BootloaderChallenge,
+            Some(argument) => match argument.strip_prefix('=').and_then(parse_response) {
+                Some(response) => InputVariant::Bootloader(response),
+                None => InputVariant::Invalid,
+            },
+            None => InputVariant::BinaryStatus,
+        },
+        #[cfg(not(feature = "bootloader"))]
+        Err(Some('b')) => InputVariant::BinaryStatus,
+
+        // Print the recent transitions of the state machine.
+        Err(Some('l')) => InputVariant::History,
+
+        // Reset chip.
+        Err(Some('r')) => InputVariant::Reset,
+
+        // If all checks fail the user hasn't inputted anything valid.
+        _ => InputVariant::Invalid,
+    }
+}
+
+/// Parses the argument of the profile command:
+/// - "f" lists the profiles.
+/// - "fs N NAME" saves the current settings to slot N.
+/// - "fl N" loads the profile in slot N.
+/// - "fb N" boots with the profile in slot N, a plain "fb" with the current settings.
+#[cfg(feature = "profiles")]
+fn parse_profile(argument: &str) -> InputVariant {
+    let mut words = argument.split_whitespace();
+    let command = words.next();
+    let slot = words
+        .next()
+        .map(|slot| slot.parse::<u8>().ok().filter(|slot| *slot < PROFILE_SLOTS));
+    let name = words.next();
+
+    if words.next().is_some() {
+        return InputVariant::Invalid;
+    }
+
+    match (command, slot, name) {
+        (None, None, None) => InputVariant::ListProfiles,
+        (Some("s"), Some(Some(slot)), Some(name)) if valid_profile_name(name) => {
+            InputVariant::SaveProfile(slot, ProfileName::new(name))
+        }
+        (Some("l"), Some(Some(slot)), None) => InputVariant::LoadProfile(slot),
+        (Some("b"), Some(Some(slot)), None) => InputVariant::SetBootProfile(Some(slot)),
+        (Some("b"), None, None) => InputVariant::SetBootProfile(None),
+        _ => InputVariant::Invalid,
+    }
+}
+
+/// An empty argument disables the threshold, so the outer `None` means invalid.
+#[cfg(feature = "supply")]
+fn parse_threshold(argument: &str) -> Option<Option<u16>> {
+    match argument.trim() {
+        "" => Some(None),
+        argument => match argument.strip_prefix('=').map(str::parse::<u16>) {
+            Some(Ok(millivolts)) if millivolts > 0 => Some(Some(millivolts)),
+            _ => None,
+        },
+    }
+}
+
+/// Parses the arguments of the TMC driver commands:
+/// - "tmc" reads the flags of the driver.
+/// - "tmc r=N" and "tmc h=N" set the run and the hold current to N mA.
+/// - "tmc c=s" selects StealthChop, "tmc c=p" SpreadCycle.
+/// - "tmc i=1" interpolates the microsteps, "tmc i=0" does not.
+#[cfg(feature = "tmc")]
+fn parse_tmc(argument: &str) -> InputVariant {
+    match argument {
+        "" => InputVariant::DriverStatus,
+        "c=s" => InputVariant::SetChopper(Chopper::StealthChop),
+        "c=p" => InputVariant::SetChopper(Chopper::SpreadCycle),
+        "i=0" => InputVariant::SetInterpolation(false),
+        "i=1" => InputVariant::SetInterpolation(true),
+        _ => match (argument.get(..2), argument.get(2..).and_then(parse_current)) {
+            (Some("r="), Some(milliamps)) => InputVariant::SetRunCurrent(milliamps),
+            (Some("h="), Some(milliamps)) => InputVariant::SetHoldCurrent(milliamps),
+            _ => InputVariant::Invalid,
+        },
+    }
+}
+
+/// The currents are stored in steps of 10 mA, the rest is dropped.
+#[cfg(feature = "tmc")]
+fn parse_current(argument: &str) -> Option<u16> {
+    match argument.parse::<u16>() {
+        Ok(milliamps) if milliamps <= MAX_CURRENT_MA => Some(milliamps / 10 * 10),
+        _ => None,
+    }
+}
+
+#[cfg(any(feature = "backup", feature = "bootloader"))]
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Parses up to one chunk of hex digits, two per byte.
+#[cfg(feature = "backup")]
+fn parse_chunk(hex: &str) -> Option<Chunk> {
+    let hex = hex.as_bytes();
+    let pairs = hex.chunks_exact(2);
+    if hex.is_empty() || !pairs.remainder().is_empty() || hex.len() > 2 * CHUNK_LENGTH {
+        return None;
+    }
+
+    let mut chunk = Chunk {
+        bytes: [0; CHUNK_LENGTH],
+        length: hex.len() / 2,
+    };
+    for (byte, digits) in chunk.bytes.iter_mut().zip(pairs) {
+        let high = (digits[0] as char).to_digit(16)?;
+        let low = (digits[1] as char).to_digit(16)?;
+        *byte = (high << 4 | low) as u8;
+    }
+    Some(chunk)
+}
+
+/// The response to a bootloader challenge has all 16 hex digits.
+#[cfg(feature = "bootloader")]
+fn parse_response(hex: &str) -> Option<u64> {
+    if hex.len() != 16 || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(feature = "profiles")]
+fn valid_profile_name(name: &str) -> bool {
+    name.len() <= PROFILE_NAME_LENGTH && name.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Parses the argument of the trim command:
+/// - "p+" or "p-" changes the trim by the default step.
+/// - "p+N" or "p-N" changes the trim by N ppm.
+/// - "p=N" sets the trim to exactly N ppm.
+fn parse_trim(argument: &str) -> InputVariant {
+    let argument = argument.trim();
+
+    if let Some(value) = argument.strip_prefix('=') {
+        return match value.parse::<i32>() {
+            Ok(trim) => InputVariant::SetTrim(trim),
+            Err(_) => InputVariant::Invalid,
+        };
+    }
+
+    match argument {
+        "+" => InputVariant::AdjustTrim(TRIM_STEP_PPM),
+        "-" => InputVariant::AdjustTrim(-TRIM_STEP_PPM),
+        _ if argument.starts_with('+') || argument.starts_with('-') => {
+            match argument.parse::<i32>() {
+                Ok(delta) => InputVariant::AdjustTrim(delta),
+                Err(_) => InputVariant::Invalid,
+            }
+        }
+        _ => InputVariant::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockSerial;
+
+    #[test]
+    fn lines_are_assembled_from_bytes() {
+        let mut serial_handler = SerialHandler::new(MockSerial::default());
+
+        serial_handler.port.0.receive("30");
+        assert!(serial_handler.handle_input().is_none());
+
+        serial_handler.port.0.receive("000\nh\n");
+        assert!(matches!(
+            serial_handler.handle_input(),
+            Some(InputVariant::TrackNewTime(Microseconds(30_000)))
+        ));
+        assert!(matches!(
+            serial_handler.handle_input(),
+            Some(InputVariant::Hold)
+        ));
+        assert!(serial_handler.port.0.output.contains("Got: 30000\n"));
+    }
+
+    #[test]
+    fn lines_with_errors_are_dropped() {
+        let mut serial_handler = SerialHandler::new(MockSerial::default());
+
+        serial_handler.port.0.receive("3");
+        serial_handler.port.0.receive_error(RxError::Framing);
+        serial_handler.port.0.receive("0\nh");
+        serial_handler.port.0.receive_error(RxError::Overrun);
+        serial_handler.port.0.receive_error(RxError::Overrun);
+        serial_handler.port.0.receive("\n");
+        for _ in 0..3 {
+            serial_handler.port.0.receive(&"a".repeat(LINE_LENGTH + 10));
+        }
+        serial_handler.port.0.receive("\nt\n");
+
+        assert!(matches!(
+            serial_handler.handle_input(),
+            Some(InputVariant::Track)
+        ));
+        assert_eq!(
+            serial_handler.port.0.output,
+            "Line dropped: framing error!\n\
+            Line dropped: overrun!\n\
+            Line dropped: overflow!\n\
+            Got: t\n"
+        );
+
+        let errors = serial_handler.rx_errors();
+        assert_eq!(errors.get(RxError::Framing), 1);
+        assert_eq!(errors.get(RxError::Overrun), 2);
+        assert_eq!(errors.get(RxError::Overflow), 1);
+        assert_eq!(errors.get(RxError::Parity), 0);
+    }
+
+    #[test]
+    fn trim_commands() {
+        assert!(matches!(
+            parse_input("p+"),
+            InputVariant::AdjustTrim(TRIM_STEP_PPM)
+        ));
+        assert!(matches!(parse_input("p-25"), InputVariant::AdjustTrim(-25)));
+        assert!(matches!(parse_input("p=-7\r"), InputVariant::SetTrim(-7)));
+        assert!(matches!(parse_input("p7"), InputVariant::Invalid));
+    }
+
+    #[test]
+    #[cfg(feature = "guiding")]
+    fn guide_commands() {
+        assert!(matches!(
+            parse_input("g+"),
+            InputVariant::Guide(GuideDirection::Faster)
+        ));
+        assert!(matches!(parse_input("g"), InputVariant::GuideDone));
+        assert!(matches!(parse_input("gx"), InputVariant::Invalid));
+    }
+
+    #[test]
+    #[cfg(not(feature = "guiding"))]
+    fn disabled_commands_are_invalid() {
+        assert!(crate::features().all(|feature| feature != "guiding"));
+        assert!(matches!(parse_input("g+"), InputVariant::Invalid));
+        assert!(matches!(parse_input("j=30"), InputVariant::Invalid));
+    }
+
+    #[test]
+    fn travel_limit_commands() {
+        assert!(matches!(
+            parse_input("m=50000"),
+            InputVariant::SetTravelLimit(Some(50_000))
+        ));
+        assert!(matches!(
+            parse_input("m"),
+            InputVariant::SetTravelLimit(None)
+        ));
+        assert!(matches!(parse_input("m=-5"), InputVariant::Invalid));
+    }
+
+    #[test]
+    fn park_commands() {
+        assert!(matches!(parse_input("k"), InputVariant::Park));
+        assert!(matches!(
+            parse_input("k=2000"),
+            InputVariant::SetParkPosition(2000)
+        ));
+        assert!(matches!(parse_input("kx"), InputVariant::Invalid));
+    }
+
+    #[test]
+    fn startup_commands() {
+        assert!(matches!(
+            parse_input("n=h"),
+            InputVariant::SetPowerOn(PowerOn::Hold)
+        ));
+        assert!(matches!(
+            parse_input("n=15"),
+            InputVariant::SetPowerOn(PowerOn::Delayed(15))
+        ));
+        assert!(matches!(parse_input("a=5"), InputVariant::ArmCountdown(5)));
+        assert!(matches!(parse_input("a"), InputVariant::CancelCountdown));
+        assert!(matches!(parse_input("a5"), InputVariant::Invalid));
+    }
+
+    #[test]
+    #[cfg(feature = "profiles")]
+    fn profile_commands() {
+        assert!(matches!(parse_input("f"), InputVariant::ListProfiles));
+        assert!(matches!(
+            parse_input("fs 1 barndoor"),
+            InputVariant::SaveProfile(1, name) if name == ProfileName::new("barndoor")
+        ));
+        assert!(matches!(parse_input("fl 2"), InputVariant::LoadProfile(2)));
+        assert!(matches!(
+            parse_input("fb"),
+            InputVariant::SetBootProfile(None)
+        ));
+        assert!(matches!(parse_input("fl 9"), InputVariant::Invalid));
+        assert!(matches!(
+            parse_input("fs 0 name_too_long"),
+            InputVariant::Invalid
+        ));
+    }
+
+    #[test]
+    fn driver_commands() {
+        assert!(matches!(parse_input("x=12"), InputVariant::Invalid));
+        assert!(matches!(
+            parse_input("v=1"),
+            InputVariant::SetReversed(true)
+        ));
+        #[cfg(feature = "guiding")]
+        assert!(matches!(
+            parse_input("j=30"),
+            InputVariant::SetGuideRate(30)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "backup")]
+    fn export_can_be_parsed_as_import() {
+        let mut serial_handler = SerialHandler::new(MockSerial::default());
+        let blob: Vec<u8> = (0..=40).collect();
+        serial_handler.send_export(|write| {
+            write(&blob[..5]);
+            write(&blob[5..]);
+        });
+
+        let output = serial_handler.port.0.output.clone();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(matches!(parse_input(lines[0]), InputVariant::ImportBegin));
+        assert!(matches!(parse_input(lines[3]), InputVariant::ImportCommit));
+
+        let mut received = Vec::new();
+        for line in &lines[1..3] {
+            match parse_input(line) {
+                InputVariant::ImportData(chunk) => received.extend_from_slice(chunk.as_bytes()),
+                _ => panic!("not a chunk: {}", line),
+            }
+        }
+        assert_eq!(received, blob);
+        assert!(matches!(parse_input("i:0G"), InputVariant::Invalid));
+        assert!(matches!(parse_input("i:ABC"), InputVariant::Invalid));
+    }
+
+    #[test]
+    fn link_commands() {
+        assert!(matches!(parse_input("q"), InputVariant::Heartbeat));
+        assert!(matches!(
+            parse_input("q=30"),
+            InputVariant::SetLinkTimeout(30)
+        ));
+        assert!(matches!(
+            parse_input("q=h"),
+            InputVariant::SetLinkLoss(LinkLoss::Hold)
+        ));
+        assert!(matches!(parse_input("q=-1"), InputVariant::Invalid));
+    }
+
+    #[test]
+    #[cfg(feature = "supply")]
+    fn supply_commands() {
+        assert!(matches!(
+            parse_input("y=11500"),
+            InputVariant::SetSupplyWarning(Some(11_500))
+        ));
+        assert!(matches!(
+            parse_input("z"),
+            InputVariant::SetSupplyCutoff(None)
+        ));
+        assert!(matches!(parse_input("z=0"), InputVariant::Invalid));
+    }
+
+    #[test]
+    #[cfg(feature = "tmc")]
+    fn tmc_commands() {
+        assert!(matches!(parse_input("t"), InputVariant::Track));
+        assert!(matches!(parse_input("tmc"), InputVariant::DriverStatus));
+        assert!(matches!(
+            parse_input("tmc r=805"),
+            InputVariant::SetRunCurrent(800)
+        ));
+        assert!(matches!(
+            parse_input("tmc h=0"),
+            InputVariant::SetHoldCurrent(0)
+        ));
+        assert!(matches!(parse_input("tmc r=1800"), InputVariant::Invalid));
+        assert!(matches!(
+            parse_input("tmc c=p"),
+            InputVariant::SetChopper(Chopper::SpreadCycle)
+        ));
+        assert!(matches!(
+            parse_input("tmc i=0"),
+            InputVariant::SetInterpolation(false)
+        ));
+        assert!(matches!(parse_input("tmc x=1"), InputVariant::Invalid));
+    }
+
+    #[test]
+    #[cfg(feature = "bootloader")]
+    fn bootloader_commands() {
+        assert!(matches!(parse_input("b"), InputVariant::BinaryStatus));
+        assert!(matches!(
+            parse_input("bootloader\r"),
+            InputVariant::BootloaderChallenge
+        ));
+        assert!(matches!(
+            parse_input("bootloader=00000000DEADbeef"),
+            InputVariant::Bootloader(0xDEAD_BEEF)
+        ));
+        assert!(matches!(parse_input("bootloader=x"), InputVariant::Invalid));
+        assert!(matches!(
+            parse_input("bootloader=4711"),
+            InputVariant::Invalid
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+//! Keeps track of the time spent tracking in the current session and
+//! estimates how long the platform can track until it reaches its travel limit.
+//! A session starts on boot and whenever the platform is back at the start
+//! of its travel.
+
+use embedded_time::duration::*;
+
+pub struct Session {
+    tracked_ms: u32,
+    last_update: u32,
+}
+
+impl Session {
+    pub fn new(now: u32) -> Self {
+        Self {
+            tracked_ms: 0,
+            last_update: now,
+        }
+    }
+
+    /// Has to be called regularly with the uptime in milliseconds. The time
+    /// since the last call is counted if the platform was tracking meanwhile.
+    /// Returns the counted milliseconds.
+    pub fn update(&mut self, now: u32, tracking: bool) -> u32 {
+        let elapsed = if tracking {
+            now.wrapping_sub(self.last_update)
+        } else {
+            0
+        };
+        self.tracked_ms = self.tracked_ms.saturating_add(elapsed);
+        self.last_update = now;
+        elapsed
+    }
+
+    pub fn restart(&mut self, now: u32) {
+        *self = Self::new(now);
+    }
+
+    pub fn tracked_seconds(&self) -> u32 {
+        self.tracked_ms / 1000
+    }
+}
+
+/// The seconds the platform can keep tracking from the given position
+/// until it reaches the travel limit.
+pub fn remaining_seconds(position: i32, travel_limit: i32, tracking_time: Microseconds) -> u32 {
+    let steps = (travel_limit as i64 - position as i64).max(0) as u64;
+    let seconds = steps * *tracking_time.integer() as u64 / 1_000_000;
+
+    seconds.min(u32::MAX as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_tracking_time_is_counted() {
+        let mut session = Session::new(1000);
+
+        session.update(3000, true);
+        session.update(10_000, false);
+        session.update(11_500, true);
+        assert_eq!(session.tracked_seconds(), 3);
+
+        session.restart(12_000);
+        assert_eq!(session.tracked_seconds(), 0);
+    }
+
+    #[test]
+    fn remaining_time_is_estimated_from_the_travel_left() {
+        assert_eq!(remaining_seconds(1000, 11_000, Microseconds(30_000)), 300);
+        assert_eq!(remaining_seconds(12_000, 11_000, Microseconds(30_000)), 0);
+    }
+}
@@ -0,0 +1,82 @@
+//! What the platform does after power-up and the countdown that starts
+//! tracking after a delay, e.g. once the target has cleared a tree line.
+
+/// The behaviour after power-up.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PowerOn {
+    Track,
+    Hold,
+    /// Hold and start tracking after the given minutes.
+    Delayed(u16),
+}
+
+impl PowerOn {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerOn::Track => "track",
+            PowerOn::Hold => "hold",
+            PowerOn::Delayed(_) => "delayed",
+        }
+    }
+}
+
+/// Counts down in milliseconds of uptime. Survives the overflow of the uptime.
+pub struct Countdown {
+    /// The start time and the duration of the armed countdown.
+    armed: Option<(u32, u32)>,
+}
+
+impl Countdown {
+    pub fn new() -> Self {
+        Self { armed: None }
+    }
+
+    pub fn arm(&mut self, now: u32, minutes: u16) {
+        self.armed = Some((now, minutes as u32 * 60_000));
+    }
+
+    pub fn cancel(&mut self) {
+        self.armed = None;
+    }
+
+    /// The seconds until the countdown expires, if it is armed.
+    pub fn remaining_seconds(&self, now: u32) -> Option<u32> {
+        self.armed
+            .map(|(start, duration)| duration.saturating_sub(now.wrapping_sub(start)) / 1000)
+    }
+
+    /// Returns true once when the countdown has expired and disarms it.
+    pub fn expired(&mut self, now: u32) -> bool {
+        match self.armed {
+            Some((start, duration)) if now.wrapping_sub(start) >= duration => {
+                self.armed = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Default for Countdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdown_expires_once() {
+        let mut countdown = Countdown::new();
+        assert!(!countdown.expired(0));
+
+        countdown.arm(u32::MAX - 1000, 2);
+        assert_eq!(countdown.remaining_seconds(u32::MAX), Some(119));
+        assert!(!countdown.expired(100_000));
+        assert!(countdown.expired(119_000));
+        assert!(!countdown.expired(119_001));
+        assert_eq!(countdown.remaining_seconds(119_001), None);
+    }
+}
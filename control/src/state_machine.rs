@@ -0,0 +1,521 @@
+//! The tracker is modelled as a finite state machine. Everything that wants to
+//! change the motion of the platform has to send an `Event` to the `EQTracker`.
+//! The tracker checks whether the transition is allowed in the current state
+//! and requests the necessary side effects through the `Actions` interface.
+
+use embedded_time::duration::*;
+
+use crate::hardware::{StepTimer, StepperOutput};
+use crate::rate::{apply_guide_rate, apply_trim, clamp_step_time, clamp_trim, GUIDE_RATE_PERCENT};
+
+/// The waiting time between two steps while slewing, rewinding or homing.
+const SLEW_TIME: Microseconds = Microseconds(1200);
+/// Half the slew speed, for a supply that can not drive the motor at full speed anymore.
+const REDUCED_SLEW_TIME: Microseconds = Microseconds(2400);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GuideDirection {
+    Faster,
+    Slower,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    Hold,
+    Tracking,
+    Guiding(GuideDirection),
+    Slewing(Direction),
+    Rewinding,
+    Homing,
+    /// Moving to the park position.
+    Parking(Direction),
+    Parked,
+    Fault,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Hold => "Hold",
+            State::Tracking => "Tracking",
+            State::Guiding(_) => "Guiding",
+            State::Slewing(_) => "Slewing",
+            State::Rewinding => "Rewinding",
+            State::Homing => "Homing",
+            State::Parking(_) => "Parking",
+            State::Parked => "Parked",
+            State::Fault => "Fault",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    Track,
+    Hold,
+    Slew(Direction),
+    Guide(GuideDirection),
+    GuideDone,
+    /// Move back to the start of the travel and continue tracking there.
+    Rewind,
+    /// Move back to the start of the travel and stay there.
+    Home,
+    /// The target of the current motion has been reached.
+    Arrived,
+    /// Move to the park position and disable the driver there.
+    Park,
+    /// Continue with the motion the platform had before parking.
+    Unpark,
+    Fault,
+    ClearFault,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Track => "track",
+            Event::Hold => "hold",
+            Event::Slew(_) => "slew",
+            Event::Guide(_) => "guide",
+            Event::GuideDone => "guide done",
+            Event::Rewind => "rewind",
+            Event::Home => "home",
+            Event::Arrived => "arrived",
+            Event::Park => "park",
+            Event::Unpark => "unpark",
+            Event::Fault => "fault",
+            Event::ClearFault => "clear fault",
+        }
+    }
+}
+
+/// The reason why an event was refused by the state machine.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransitionError {
+    /// The platform is parked and has to be unparked first.
+    Parked,
+    /// The platform is in the fault state and the fault has to be cleared first.
+    Faulted,
+    /// The event makes no sense in the current state.
+    NotAllowed,
+}
+
+/// The side effects the state machine can request from the hardware.
+/// Every motor with a step timer and a stepper output is able to carry them out.
+pub trait Actions: StepperOutput + StepTimer {}
+
+impl<T: StepperOutput + StepTimer> Actions for T {}
+
+pub struct EQTracker {
+    waiting_time: Microseconds,
+    trim_ppm: i32,
+    guide_rate_percent: u8,
+    travel_limit: Option<i32>,
+    park_position: i32,
+    reduced_slew: bool,
+    /// The state that is restored when unparking.
+    resume: State,
+    state: State,
+}
+
+impl EQTracker {
+    pub fn new(waiting_time: Microseconds, trim_ppm: i32) -> Self {
+        EQTracker {
+            waiting_time: clamp_step_time(*waiting_time.integer() as u64),
+            trim_ppm: clamp_trim(trim_ppm),
+            guide_rate_percent: GUIDE_RATE_PERCENT,
+            travel_limit: None,
+            park_position: 0,
+            reduced_slew: false,
+            resume: State::Hold,
+            state: State::Hold,
+        }
+    }
+
+    pub fn get_state(&self) -> State {
+        self.state
+    }
+
+    /// Feeds an event into the state machine. On success the new state is returned.
+    /// If the event is refused, the current state is kept and no action is requested.
+    pub fn handle<A: Actions>(
+        &mut self,
+        event: Event,
+        actions: &mut A,
+    ) -> Result<State, TransitionError> {
+        let next = self.next_state(event, actions.position())?;
+
+        // Remember the motion to continue with when unparking.
+        if matches!(next, State::Parking(_)) && !matches!(self.state, State::Parking(_)) {
+            self.resume = match self.state {
+                State::Tracking | State::Guiding(_) | State::Rewinding => State::Tracking,
+                _ => State::Hold,
+            };
+        }
+
+        self.enter(next, actions);
+        Ok(next)
+    }
+
+    /// Enters the parked state after a power cycle. The step counter has to be
+    /// restored by the caller.
+    pub fn restore_park<A: Actions>(&mut self, resume: State, actions: &mut A) {
+        self.resume = resume;
+        self.enter(State::Parked, actions);
+    }
+
+    /// Has to be called regularly with the current step position. Raises the
+    /// `Arrived` event when a target is reached and the `Fault` event when
+    /// the platform runs past the end of its travel. The raised event is returned.
+    pub fn update<A: Actions>(&mut self, position: i32, actions: &mut A) -> Option<Event> {
+        let event = self.check_position(position)?;
+        self.handle(event, actions).ok()?;
+        Some(event)
+    }
+
+    fn check_position(&self, position: i32) -> Option<Event> {
+        if let Some(limit) = self.travel_limit {
+            // Only forward motion is stopped, so the platform can always be rewound.
+            if position > limit && self.moves_forward() {
+                return Some(Event::Fault);
+            }
+        }
+
+        let arrived = match (self.state, self.target()) {
+            (State::Parking(Direction::Forward), Some(target)) => position >= target,
+            (_, Some(target)) => position <= target,
+            (_, None) => false,
+        };
+
+        if arrived {
+            Some(Event::Arrived)
+        } else {
+            None
+        }
+    }
+
+    /// The step position the current motion is heading to, if there is any.
+    pub fn target(&self) -> Option<i32> {
+        match self.state {
+            State::Rewinding | State::Homing => Some(0),
+            State::Parking(_) => Some(self.park_position),
+            _ => None,
+        }
+    }
+
+    /// Tracking with or without a guide pulse on top of it.
+    pub fn is_tracking(&self) -> bool {
+        matches!(self.state, State::Tracking | State::Guiding(_))
+    }
+
+    fn moves_forward(&self) -> bool {
+        matches!(
+            self.state,
+            State::Tracking
+                | State::Guiding(_)
+                | State::Slewing(Direction::Forward)
+                | State::Parking(Direction::Forward)
+        )
+    }
+
+    pub fn get_travel_limit(&self) -> Option<i32> {
+        self.travel_limit
+    }
+
+    pub fn set_travel_limit(&mut self, limit: Option<i32>) {
+        self.travel_limit = limit;
+    }
+
+    pub fn get_park_position(&self) -> i32 {
+        self.park_position
+    }
+
+    pub fn set_park_position(&mut self, position: i32) {
+        self.park_position = position;
+    }
+
+    /// The state that is restored when unparking.
+    pub fn get_resume(&self) -> State {
+        self.resume
+    }
+
+    pub fn get_waiting_time(&self) -> Microseconds {
+        self.waiting_time
+    }
+
+    /// The time is cut to what the step timer can count.
+    pub fn set_waiting_time<A: Actions>(&mut self, duration: Microseconds, actions: &mut A) {
+        self.waiting_time = clamp_step_time(*duration.integer() as u64);
+        self.refresh_rate(actions);
+    }
+
+    pub fn get_trim(&self) -> i32 {
+        self.trim_ppm
+    }
+
+    pub fn set_trim<A: Actions>(&mut self, trim_ppm: i32, actions: &mut A) {
+        self.trim_ppm = clamp_trim(trim_ppm);
+        self.refresh_rate(actions);
+    }
+
+    pub fn adjust_trim<A: Actions>(&mut self, delta_ppm: i32, actions: &mut A) {
+        self.set_trim(self.trim_ppm.saturating_add(delta_ppm), actions);
+    }
+
+    pub fn get_guide_rate(&self) -> u8 {
+        self.guide_rate_percent
+    }
+
+    pub fn set_guide_rate<A: Actions>(&mut self, rate_percent: u8, actions: &mut A) {
+        self.guide_rate_percent = rate_percent;
+        self.refresh_rate(actions);
+    }
+
+    /// Slows down the slews, also the one that is currently running.
+    pub fn set_reduced_slew<A: Actions>(&mut self, reduced: bool, actions: &mut A) {
+        self.reduced_slew = reduced;
+        if matches!(
+            self.state,
+            State::Slewing(_) | State::Parking(_) | State::Rewinding | State::Homing
+        ) {
+            actions.set_step_time(self.slew_time());
+        }
+    }
+
+    fn slew_time(&self) -> Microseconds {
+        if self.reduced_slew {
+            REDUCED_SLEW_TIME
+        } else {
+            SLEW_TIME
+        }
+    }
+
+    /// The waiting time that is actually used for tracking.
+    /// It is the base waiting time with the trim applied on top of it.
+    pub fn get_tracking_time(&self) -> Microseconds {
+        apply_trim(self.waiting_time, self.trim_ppm)
+    }
+
+    /// The guarded transitions of the state machine.
+    fn next_state(&self, event: Event, position: i32) -> Result<State, TransitionError> {
+        match (self.state, event) {
+            // A fault is always accepted and can only be left by clearing it.
+            (_, Event::Fault) => Ok(State::Fault),
+            (State::Fault, Event::ClearFault) => Ok(State::Hold),
+            (State::Fault, _) => Err(TransitionError::Faulted),
+
+            // No motion is allowed while parked.
+            (State::Parked, Event::Unpark) => Ok(self.resume),
+            (State::Parked, _) => Err(TransitionError::Parked),
+
+            (_, Event::Track) => Ok(State::Tracking),
+            (_, Event::Hold) => Ok(State::Hold),
+            (_, Event::Slew(direction)) => Ok(State::Slewing(direction)),
+            (_, Event::Rewind) => Ok(State::Rewinding),
+            (_, Event::Home) => Ok(State::Homing),
+            (_, Event::Park) if position > self.park_position => {
+                Ok(State::Parking(Direction::Backward))
+            }
+            (_, Event::Park) => Ok(State::Parking(Direction::Forward)),
+
+            // Guide pulses only make sense on top of tracking.
+            (State::Tracking, Event::Guide(direction))
+            | (State::Guiding(_), Event::Guide(direction)) => Ok(State::Guiding(direction)),
+            (State::Guiding(_), Event::GuideDone) => Ok(State::Tracking),
+
+            (State::Rewinding, Event::Arrived) => Ok(State::Tracking),
+            (State::Homing, Event::Arrived) => Ok(State::Hold),
+            (State::Parking(_), Event::Arrived) => Ok(State::Parked),
+
+            _ => Err(TransitionError::NotAllowed),
+        }
+    }
+
+    /// Requests the side effects of the new state and switches to it.
+    fn enter<A: Actions>(&mut self, state: State, actions: &mut A) {
+        self.state = state;
+        actions.set_enabled(state != State::Parked);
+
+        match state {
+            State::Hold | State::Parked | State::Fault => actions.set_stepping(false),
+            State::Tracking | State::Guiding(_) => {
+                actions.set_direction(Direction::Forward);
+                self.refresh_rate(actions);
+                actions.set_stepping(true);
+            }
+            State::Slewing(direction) => {
+                actions.set_direction(direction);
+                actions.set_step_time(self.slew_time());
+                actions.set_stepping(true);
+            }
+            State::Parking(direction) => {
+                actions.set_direction(direction);
+                actions.set_step_time(self.slew_time());
+                actions.set_stepping(true);
+            }
+            State::Rewinding | State::Homing => {
+                actions.set_direction(Direction::Backward);
+                actions.set_step_time(self.slew_time());
+                actions.set_stepping(true);
+            }
+        }
+    }
+
+    /// Applies a changed tracking rate when it is currently in use.
+    fn refresh_rate<A: Actions>(&self, actions: &mut A) {
+        match self.state {
+            State::Tracking => actions.set_step_time(self.get_tracking_time()),
+            State::Guiding(direction) => actions.set_step_time(apply_guide_rate(
+                self.get_tracking_time(),
+                direction,
+                self.guide_rate_percent,
+            )),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockMotor;
+    use crate::rate::MAX_STEP_TIME;
+
+    fn tracker() -> (EQTracker, MockMotor) {
+        let mut motor = MockMotor::default();
+        let mut tracker = EQTracker::new(Microseconds(30_000), 0);
+        tracker.handle(Event::Track, &mut motor).unwrap();
+        (tracker, motor)
+    }
+
+    #[test]
+    fn tracking_requests_the_tracking_rate() {
+        let (_, motor) = tracker();
+
+        assert!(motor.stepping);
+        assert_eq!(motor.direction, Direction::Forward);
+        assert_eq!(motor.step_time, Microseconds(30_000_u32));
+    }
+
+    #[test]
+    fn slow_guiding_stays_within_the_timer_limit() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.set_waiting_time(Microseconds(1_000_000), &mut motor);
+        assert_eq!(tracker.get_waiting_time(), MAX_STEP_TIME);
+
+        tracker.set_waiting_time(Microseconds(300_000), &mut motor);
+        tracker
+            .handle(Event::Guide(GuideDirection::Slower), &mut motor)
+            .unwrap();
+        assert_eq!(motor.step_time, MAX_STEP_TIME);
+    }
+
+    #[test]
+    fn no_slewing_while_parked() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.handle(Event::Park, &mut motor).unwrap();
+        tracker.update(0, &mut motor);
+        assert_eq!(tracker.get_state(), State::Parked);
+
+        assert_eq!(
+            tracker.handle(Event::Slew(Direction::Forward), &mut motor),
+            Err(TransitionError::Parked)
+        );
+        assert!(!motor.stepping);
+
+        assert_eq!(
+            tracker.handle(Event::Unpark, &mut motor),
+            Ok(State::Tracking)
+        );
+    }
+
+    #[test]
+    fn parking_moves_to_the_park_position() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.set_park_position(500);
+        motor.position = 800;
+
+        tracker.handle(Event::Park, &mut motor).unwrap();
+        assert_eq!(motor.direction, Direction::Backward);
+        assert_eq!(tracker.update(600, &mut motor), None);
+        assert_eq!(tracker.update(500, &mut motor), Some(Event::Arrived));
+
+        assert_eq!(tracker.get_state(), State::Parked);
+        assert!(!motor.enabled);
+        assert_eq!(tracker.get_resume(), State::Tracking);
+    }
+
+    #[test]
+    fn reduced_slew_applies_to_the_running_slew() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.set_reduced_slew(true, &mut motor);
+        assert_eq!(motor.step_time, Microseconds(30_000_u32));
+
+        tracker.handle(Event::Rewind, &mut motor).unwrap();
+        assert_eq!(motor.step_time, REDUCED_SLEW_TIME);
+        tracker.set_reduced_slew(false, &mut motor);
+        assert_eq!(motor.step_time, SLEW_TIME);
+    }
+
+    #[test]
+    fn fault_is_only_left_by_clearing_it() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.handle(Event::Fault, &mut motor).unwrap();
+
+        assert_eq!(
+            tracker.handle(Event::Track, &mut motor),
+            Err(TransitionError::Faulted)
+        );
+        assert_eq!(
+            tracker.handle(Event::ClearFault, &mut motor),
+            Ok(State::Hold)
+        );
+    }
+
+    #[test]
+    fn guiding_only_on_top_of_tracking() {
+        let (mut tracker, mut motor) = tracker();
+
+        tracker
+            .handle(Event::Guide(GuideDirection::Slower), &mut motor)
+            .unwrap();
+        assert_eq!(motor.step_time, Microseconds(60_000_u32));
+        tracker.handle(Event::GuideDone, &mut motor).unwrap();
+        assert_eq!(motor.step_time, Microseconds(30_000_u32));
+
+        tracker.handle(Event::Hold, &mut motor).unwrap();
+        assert_eq!(
+            tracker.handle(Event::Guide(GuideDirection::Faster), &mut motor),
+            Err(TransitionError::NotAllowed)
+        );
+    }
+
+    #[test]
+    fn rewind_continues_tracking_at_the_start() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.handle(Event::Rewind, &mut motor).unwrap();
+        assert_eq!(motor.direction, Direction::Backward);
+
+        assert_eq!(tracker.update(10, &mut motor), None);
+        assert_eq!(tracker.get_state(), State::Rewinding);
+        assert_eq!(tracker.update(0, &mut motor), Some(Event::Arrived));
+        assert_eq!(tracker.get_state(), State::Tracking);
+        assert_eq!(motor.direction, Direction::Forward);
+    }
+
+    #[test]
+    fn travel_limit_raises_a_fault() {
+        let (mut tracker, mut motor) = tracker();
+        tracker.set_travel_limit(Some(100));
+
+        assert_eq!(tracker.update(101, &mut motor), Some(Event::Fault));
+        assert_eq!(tracker.get_state(), State::Fault);
+        assert!(!motor.stepping);
+    }
+}
@@ -0,0 +1,170 @@
+//! Watches the supply voltage. The platforms run from 12 V batteries, and a
+//! sagging supply makes the stepper lose steps long before the controller
+//! browns out. The readings of the firmware are smoothed and compared with the
+//! configured thresholds. Below the warning threshold the user is warned and
+//! the slews get slower, below the cutoff the platform parks while it still can.
+
+/// The time between two readings.
+const SAMPLE_INTERVAL_MS: u32 = 100;
+/// Every reading has a weight of 1/8, so a single step pulse does not count.
+const SMOOTHING_SHIFT: u32 = 3;
+/// A level is only left when the voltage is this far above its threshold again.
+const HYSTERESIS_MV: u16 = 200;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SupplyLevel {
+    Normal,
+    /// Below the warning threshold.
+    Low,
+    /// Below the cutoff threshold.
+    Critical,
+}
+
+impl SupplyLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SupplyLevel::Normal => "ok",
+            SupplyLevel::Low => "low",
+            SupplyLevel::Critical => "critical",
+        }
+    }
+}
+
+/// The thresholds in millivolts, `None` disables a threshold.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Thresholds {
+    pub warning: Option<u16>,
+    pub cutoff: Option<u16>,
+}
+
+pub struct SupplyMonitor {
+    /// The smoothed voltage, scaled by the smoothing factor.
+    smoothed: Option<u32>,
+    level: SupplyLevel,
+    last_sample: u32,
+}
+
+impl SupplyMonitor {
+    pub fn new(now: u32) -> Self {
+        Self {
+            smoothed: None,
+            level: SupplyLevel::Normal,
+            last_sample: now,
+        }
+    }
+
+    /// Tells whether the next reading is due.
+    pub fn is_due(&self, now: u32) -> bool {
+        now.wrapping_sub(self.last_sample) >= SAMPLE_INTERVAL_MS
+    }
+
+    /// Adds a reading. Returns the new level when it has changed.
+    pub fn update(
+        &mut self,
+        now: u32,
+        millivolts: u16,
+        thresholds: Thresholds,
+    ) -> Option<SupplyLevel> {
+        self.last_sample = now;
+        let reading = millivolts as u32;
+        self.smoothed = Some(match self.smoothed {
+            Some(smoothed) => smoothed - (smoothed >> SMOOTHING_SHIFT) + reading,
+            None => reading << SMOOTHING_SHIFT,
+        });
+
+        let level = self.classify(thresholds);
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+
+    /// The smoothed voltage, `None` until the first reading.
+    pub fn millivolts(&self) -> Option<u16> {
+        self.smoothed
+            .map(|smoothed| (smoothed >> SMOOTHING_SHIFT) as u16)
+    }
+
+    pub fn level(&self) -> SupplyLevel {
+        self.level
+    }
+
+    fn classify(&self, thresholds: Thresholds) -> SupplyLevel {
+        let millivolts = match self.millivolts() {
+            Some(millivolts) => millivolts,
+            None => return SupplyLevel::Normal,
+        };
+        let below = |threshold: Option<u16>, active: bool| match threshold {
+            Some(threshold) if active => millivolts < threshold.saturating_add(HYSTERESIS_MV),
+            Some(threshold) => millivolts < threshold,
+            None => false,
+        };
+
+        if below(thresholds.cutoff, self.level == SupplyLevel::Critical) {
+            SupplyLevel::Critical
+        } else if below(thresholds.warning, self.level != SupplyLevel::Normal) {
+            SupplyLevel::Low
+        } else {
+            SupplyLevel::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        warning: Some(11_500),
+        cutoff: Some(10_800),
+    };
+
+    #[test]
+    fn readings_are_smoothed() {
+        let mut monitor = SupplyMonitor::new(0);
+        assert!(!monitor.is_due(99));
+        assert!(monitor.is_due(100));
+        assert_eq!(monitor.millivolts(), None);
+
+        monitor.update(100, 12_000, THRESHOLDS);
+        assert_eq!(monitor.millivolts(), Some(12_000));
+
+        // A single dip while the motor steps is no low battery.
+        assert_eq!(monitor.update(200, 10_000, THRESHOLDS), None);
+        assert_eq!(monitor.millivolts(), Some(11_750));
+        assert!(!monitor.is_due(250));
+    }
+
+    #[test]
+    fn levels_are_left_with_hysteresis() {
+        let mut monitor = SupplyMonitor::new(0);
+        monitor.update(0, 11_400, THRESHOLDS);
+        assert_eq!(monitor.level(), SupplyLevel::Low);
+
+        assert_eq!(monitor.update(0, 11_400, THRESHOLDS), None);
+        for _ in 0..40 {
+            monitor.update(0, 10_500, THRESHOLDS);
+        }
+        assert_eq!(monitor.level(), SupplyLevel::Critical);
+
+        // Without the load of the motor the battery recovers a bit.
+        for _ in 0..40 {
+            monitor.update(0, 10_900, THRESHOLDS);
+        }
+        assert_eq!(monitor.level(), SupplyLevel::Critical);
+        for _ in 0..40 {
+            monitor.update(0, 11_600, THRESHOLDS);
+        }
+        assert_eq!(monitor.level(), SupplyLevel::Low);
+
+        let disabled = Thresholds {
+            warning: None,
+            cutoff: None,
+        };
+        assert_eq!(
+            monitor.update(0, 11_600, disabled),
+            Some(SupplyLevel::Normal)
+        );
+    }
+}
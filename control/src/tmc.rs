@@ -0,0 +1,326 @@
+//! The TMC2208 and TMC2209 stepper drivers. Their currents, microsteps and
+//! chopper are set over a single-wire UART instead of pins. Every access is a
+//! datagram: a write carries the register and 32 bits of data, a read request
+//! is answered with a datagram of the same shape. All of them end with a CRC-8.
+//!
+//! The settings are turned into register values here, the firmware only moves
+//! the bytes. `RegisterModel` answers the datagrams like the chip does, so the
+//! driver can be tested on the host. The registers not used here keep their
+//! reset values.
+
+use crate::config::Config;
+use crate::hardware::DriverUart;
+
+pub const WRITE_LENGTH: usize = 8;
+pub const READ_LENGTH: usize = 4;
+
+const SYNC: u8 = 0x05;
+/// The TMC2209 takes its node address from MS1 and MS2, which are both low.
+/// The TMC2208 has no address and ignores it.
+const NODE_ADDRESS: u8 = 0x00;
+/// The replies of the driver carry this address instead.
+const MASTER_ADDRESS: u8 = 0xFF;
+const WRITE: u8 = 0x80;
+
+pub const GCONF: u8 = 0x00;
+/// Counts the accepted writes, modulo 256.
+pub const IFCNT: u8 = 0x02;
+pub const IHOLD_IRUN: u8 = 0x10;
+pub const CHOPCONF: u8 = 0x6C;
+pub const DRV_STATUS: u8 = 0x6F;
+
+/// The current is set with the registers instead of the VREF potentiometer,
+/// the UART replaces the PDN input and the microsteps come from CHOPCONF.
+const GCONF_BASE: u32 = 1 << 6 | 1 << 7 | 1 << 8;
+const EN_SPREADCYCLE: u32 = 1 << 2;
+
+/// The motor goes from the run to the hold current in about 0.3 s.
+const IHOLDDELAY: u32 = 8;
+
+/// TOFF and HSTRT of the reset value.
+const CHOPCONF_BASE: u32 = 0x53;
+const MRES_SHIFT: u32 = 24;
+const INTPOL: u32 = 1 << 28;
+
+/// The RMS current at the largest current scale of 31, with the 110 mΩ sense
+/// resistors of the common driver modules and the high sense voltage range.
+const FULL_SCALE_MA: u32 = 1768;
+/// The full scale rounded down to the 10 mA steps the currents are stored in.
+pub const MAX_CURRENT_MA: u16 = 1760;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Chopper {
+    /// Silent, the right choice for tracking.
+    StealthChop,
+    /// More torque at higher speeds.
+    SpreadCycle,
+}
+
+impl Chopper {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chopper::StealthChop => "stealth",
+            Chopper::SpreadCycle => "spread",
+        }
+    }
+}
+
+/// The flags of DRV_STATUS.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DriverStatus {
+    /// The driver has shut down until it has cooled off.
+    pub overtemperature: bool,
+    pub temperature_warning: bool,
+    /// A coil is shorted to ground or to the supply.
+    pub short: bool,
+    /// A coil seems to be disconnected. Only meaningful while the motor turns.
+    pub open_load: bool,
+    pub stealth: bool,
+    pub standstill: bool,
+    /// The current scale in use.
+    pub current_scale: u8,
+}
+
+impl DriverStatus {
+    pub fn from_bits(bits: u32) -> Self {
+        let bit = |index: u32| bits & 1 << index != 0;
+        Self {
+            overtemperature: bit(1),
+            temperature_warning: bit(0),
+            short: bits & 0b11_1100 != 0,
+            open_load: bit(6) || bit(7),
+            stealth: bit(30),
+            standstill: bit(31),
+            current_scale: (bits >> 16 & 0x1F) as u8,
+        }
+    }
+}
+
+/// The CRC-8 of the datagrams with the polynomial x^8 + x^2 + x + 1.
+/// The bytes are processed starting with their lowest bit.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0_u8;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            crc = if (crc >> 7) ^ (byte & 1) != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+pub fn write_datagram(register: u8, value: u32) -> [u8; WRITE_LENGTH] {
+    let mut datagram = [SYNC, NODE_ADDRESS, register | WRITE, 0, 0, 0, 0, 0];
+    datagram[3..7].copy_from_slice(&value.to_be_bytes());
+    datagram[7] = crc8(&datagram[..7]);
+    datagram
+}
+
+pub fn read_request(register: u8) -> [u8; READ_LENGTH] {
+    let mut request = [SYNC, NODE_ADDRESS, register, 0];
+    request[3] = crc8(&request[..3]);
+    request
+}
+
+/// Checks the reply to a read request of the register and returns its value.
+pub fn decode_reply(register: u8, reply: &[u8]) -> Option<u32> {
+    if reply.len() != WRITE_LENGTH
+        || reply[..3] != [SYNC, MASTER_ADDRESS, register]
+        || reply[7] != crc8(&reply[..7])
+    {
+        return None;
+    }
+    Some(u32::from_be_bytes([reply[3], reply[4], reply[5], reply[6]]))
+}
+
+/// The current scale that comes closest to the RMS current.
+pub fn current_scale(milliamps: u16) -> u8 {
+    let scale = (milliamps as u32 * 32 + FULL_SCALE_MA / 2) / FULL_SCALE_MA;
+    scale.saturating_sub(1).min(31) as u8
+}
+
+/// The register values for the settings, in the order they are written.
+pub fn registers(config: &Config) -> [(u8, u32); 3] {
+    let gconf = match config.chopper {
+        Chopper::StealthChop => GCONF_BASE,
+        Chopper::SpreadCycle => GCONF_BASE | EN_SPREADCYCLE,
+    };
+    let ihold_irun = current_scale(config.hold_current_ma) as u32
+        | (current_scale(config.run_current_ma) as u32) << 8
+        | IHOLDDELAY << 16;
+    // MRES counts down from 256 microsteps.
+    let mres = 8 - config.microsteps.trailing_zeros();
+    let mut chopconf = CHOPCONF_BASE | mres << MRES_SHIFT;
+    if config.interpolation {
+        chopconf |= INTPOL;
+    }
+
+    [
+        (GCONF, gconf),
+        (IHOLD_IRUN, ihold_irun),
+        (CHOPCONF, chopconf),
+    ]
+}
+
+pub fn read<U: DriverUart>(uart: &mut U, register: u8) -> Option<u32> {
+    let mut reply = [0_u8; WRITE_LENGTH];
+    if !uart.transfer(&read_request(register), &mut reply) {
+        return None;
+    }
+    decode_reply(register, &reply)
+}
+
+/// Writes the settings. The driver counts the writes it accepted, so the
+/// counter is read before and after to notice a driver that missed one.
+pub fn configure<U: DriverUart>(uart: &mut U, config: &Config) -> bool {
+    let before = match read(uart, IFCNT) {
+        Some(count) => count,
+        None => return false,
+    };
+
+    let registers = registers(config);
+    for (register, value) in registers.iter() {
+        uart.send(&write_datagram(*register, *value));
+    }
+    read(uart, IFCNT) == Some((before + registers.len() as u32) & 0xFF)
+}
+
+pub fn read_status<U: DriverUart>(uart: &mut U) -> Option<DriverStatus> {
+    read(uart, DRV_STATUS).map(DriverStatus::from_bits)
+}
+
+/// Answers the datagrams like the chip. Other registers read as zero.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct RegisterModel {
+    pub gconf: u32,
+    pub ihold_irun: u32,
+    pub chopconf: u32,
+    pub drv_status: u32,
+    pub ifcnt: u8,
+    /// A driver without power or with a broken wire does not answer.
+    pub disconnected: bool,
+}
+
+impl RegisterModel {
+    /// Takes a datagram. Only a valid read request is answered.
+    pub fn receive(&mut self, datagram: &[u8]) -> Option<[u8; WRITE_LENGTH]> {
+        let length = datagram.len();
+        if self.disconnected
+            || !(length == WRITE_LENGTH || length == READ_LENGTH)
+            || datagram[..2] != [SYNC, NODE_ADDRESS]
+            || datagram[length - 1] != crc8(&datagram[..length - 1])
+        {
+            return None;
+        }
+
+        let register = datagram[2] & !WRITE;
+        if length == WRITE_LENGTH {
+            let value = u32::from_be_bytes([datagram[3], datagram[4], datagram[5], datagram[6]]);
+            match register {
+                GCONF => self.gconf = value,
+                IHOLD_IRUN => self.ihold_irun = value,
+                CHOPCONF => self.chopconf = value,
+                _ => (),
+            }
+            self.ifcnt = self.ifcnt.wrapping_add(1);
+            return None;
+        }
+
+        let value = match register {
+            GCONF => self.gconf,
+            IFCNT => self.ifcnt as u32,
+            IHOLD_IRUN => self.ihold_irun,
+            CHOPCONF => self.chopconf,
+            DRV_STATUS => self.drv_status,
+            _ => 0,
+        };
+        let mut reply = [SYNC, MASTER_ADDRESS, register, 0, 0, 0, 0, 0];
+        reply[3..7].copy_from_slice(&value.to_be_bytes());
+        reply[7] = crc8(&reply[..7]);
+        Some(reply)
+    }
+}
+
+impl DriverUart for RegisterModel {
+    fn send(&mut self, datagram: &[u8]) {
+        self.receive(datagram);
+    }
+
+    fn transfer(&mut self, request: &[u8], reply: &mut [u8]) -> bool {
+        match self.receive(request) {
+            Some(answer) if answer.len() == reply.len() => {
+                reply.copy_from_slice(&answer);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagrams_carry_the_crc() {
+        assert_eq!(read_request(GCONF), [0x05, 0x00, 0x00, 0x48]);
+
+        let datagram = write_datagram(IHOLD_IRUN, 0x0008_0A05);
+        assert_eq!(datagram[..7], [0x05, 0x00, 0x90, 0x00, 0x08, 0x0A, 0x05]);
+        assert_eq!(datagram[7], crc8(&datagram[..7]));
+
+        let mut reply = [0x05, 0xFF, DRV_STATUS, 0xC0, 0x0C, 0x00, 0x02, 0];
+        reply[7] = crc8(&reply[..7]);
+        assert_eq!(decode_reply(DRV_STATUS, &reply), Some(0xC00C_0002));
+        assert_eq!(decode_reply(GCONF, &reply), None);
+        reply[6] ^= 1;
+        assert_eq!(decode_reply(DRV_STATUS, &reply), None);
+    }
+
+    #[test]
+    fn settings_are_turned_into_registers() {
+        let config = Config {
+            microsteps: 16,
+            run_current_ma: 800,
+            hold_current_ma: 400,
+            chopper: Chopper::SpreadCycle,
+            interpolation: true,
+            ..Config::default()
+        };
+        assert_eq!(current_scale(800), 13);
+        assert_eq!(current_scale(0), 0);
+        assert_eq!(current_scale(MAX_CURRENT_MA), 31);
+
+        let [gconf, ihold_irun, chopconf] = registers(&config);
+        assert_eq!(gconf, (GCONF, 0x1C4));
+        assert_eq!(ihold_irun, (IHOLD_IRUN, 0x0008_0D06));
+        assert_eq!(chopconf, (CHOPCONF, 0x1400_0053));
+    }
+
+    #[test]
+    fn configuration_is_verified_with_the_counter() {
+        let mut driver = RegisterModel {
+            ifcnt: 254,
+            drv_status: 1 << 1 | 1 << 7 | 13 << 16 | 1 << 31,
+            ..RegisterModel::default()
+        };
+        let config = Config::default();
+        assert!(configure(&mut driver, &config));
+        assert_eq!(driver.ifcnt, 1);
+        assert_eq!(driver.chopconf, registers(&config)[2].1);
+
+        let status = read_status(&mut driver).unwrap();
+        assert!(status.overtemperature && status.open_load && status.standstill);
+        assert!(!status.short && !status.stealth);
+        assert_eq!(status.current_scale, 13);
+
+        driver.disconnected = true;
+        assert!(!configure(&mut driver, &config));
+        assert_eq!(read_status(&mut driver), None);
+    }
+}
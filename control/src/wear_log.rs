@@ -0,0 +1,202 @@
+//! A wear-levelled circular log for the values that change often, like the
+//! start counter. Every update goes to the next entry of a reserved EEPROM
+//! region instead of rewriting the same cells, so the wear is spread over all
+//! entries. Each entry carries a sequence number and a CRC. After a reset the
+//! newest valid entry is used, even if the last write was interrupted.
+
+use core::marker::PhantomData;
+
+use crate::eeprom::crc16;
+use crate::hardware::Storage;
+
+/// The longest record a log can hold.
+const MAX_RECORD_LENGTH: usize = 20;
+const SEQUENCE_LENGTH: usize = 2;
+const CRC_LENGTH: usize = 2;
+
+/// A value kept in a log.
+pub trait Record: Sized {
+    /// The length of the encoded value, at most `MAX_RECORD_LENGTH`.
+    const LENGTH: usize;
+
+    fn encode(&self, data: &mut [u8]);
+    fn decode(data: &[u8]) -> Self;
+}
+
+/// The part of the EEPROM a log lives in.
+#[derive(Clone, Copy)]
+pub struct Region {
+    pub base: u16,
+    pub entries: usize,
+}
+
+/// The region of the counters fills the last quarter of the EEPROM of the Atmega328p.
+pub const COUNTERS_REGION: Region = Region {
+    base: 0x0300,
+    entries: 21,
+};
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Counters {
+    pub startups: u32,
+    /// The total time spent tracking.
+    pub usage_minutes: u32,
+}
+
+impl Record for Counters {
+    const LENGTH: usize = 8;
+
+    fn encode(&self, data: &mut [u8]) {
+        data[0..4].copy_from_slice(&self.startups.to_be_bytes());
+        data[4..8].copy_from_slice(&self.usage_minutes.to_be_bytes());
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        Self {
+            startups: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            usage_minutes: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        }
+    }
+}
+
+pub struct WearLog<R> {
+    region: Region,
+    next: usize,
+    sequence: u16,
+    record: PhantomData<R>,
+}
+
+impl<R: Record> WearLog<R> {
+    /// Searches the region for the newest valid entry.
+    pub fn load<S: Storage>(region: Region, storage: &S) -> (Self, Option<R>) {
+        let mut newest: Option<(usize, u16, R)> = None;
+
+        for index in 0..region.entries {
+            if let Some((sequence, record)) = read_entry(storage, region, index) {
+                // The sequence number wraps, so the newest entry is the one
+                // every other entry is behind of.
+                let is_newer = match newest {
+                    Some((_, newest_sequence, _)) => {
+                        (sequence.wrapping_sub(newest_sequence) as i16) > 0
+                    }
+                    None => true,
+                };
+                if is_newer {
+                    newest = Some((index, sequence, record));
+                }
+            }
+        }
+
+        match newest {
+            Some((index, sequence, record)) => (
+                Self {
+                    region,
+                    next: (index + 1) % region.entries,
+                    sequence: sequence.wrapping_add(1),
+                    record: PhantomData,
+                },
+                Some(record),
+            ),
+            None => (
+                Self {
+                    region,
+                    next: 0,
+                    sequence: 0,
+                    record: PhantomData,
+                },
+                None,
+            ),
+        }
+    }
+
+    /// Writes the value to the next entry, overwriting the oldest one.
+    pub fn write<S: Storage>(&mut self, record: &R, storage: &mut S) {
+        let mut entry = [0_u8; SEQUENCE_LENGTH + MAX_RECORD_LENGTH + CRC_LENGTH];
+        let crc_offset = SEQUENCE_LENGTH + R::LENGTH;
+        entry[..SEQUENCE_LENGTH].copy_from_slice(&self.sequence.to_be_bytes());
+        record.encode(&mut entry[SEQUENCE_LENGTH..crc_offset]);
+        let crc = crc16(&entry[..crc_offset]);
+        entry[crc_offset..crc_offset + CRC_LENGTH].copy_from_slice(&crc.to_be_bytes());
+
+        storage.write(
+            address::<R>(self.region, self.next),
+            &entry[..crc_offset + CRC_LENGTH],
+        );
+
+        self.next = (self.next + 1) % self.region.entries;
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
+
+fn address<R: Record>(region: Region, index: usize) -> u16 {
+    region.base + (index * (SEQUENCE_LENGTH + R::LENGTH + CRC_LENGTH)) as u16
+}
+
+fn read_entry<S: Storage, R: Record>(
+    storage: &S,
+    region: Region,
+    index: usize,
+) -> Option<(u16, R)> {
+    let mut entry = [0_u8; SEQUENCE_LENGTH + MAX_RECORD_LENGTH + CRC_LENGTH];
+    let crc_offset = SEQUENCE_LENGTH + R::LENGTH;
+    let entry = &mut entry[..crc_offset + CRC_LENGTH];
+    storage.read(address::<R>(region, index), entry);
+
+    if u16::from_be_bytes([entry[crc_offset], entry[crc_offset + 1]]) != crc16(&entry[..crc_offset])
+    {
+        return None;
+    }
+
+    let record = R::decode(&entry[SEQUENCE_LENGTH..crc_offset]);
+    Some((u16::from_be_bytes([entry[0], entry[1]]), record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockStorage;
+
+    fn counters(startups: u32) -> Counters {
+        Counters {
+            startups,
+            usage_minutes: 0,
+        }
+    }
+
+    #[test]
+    fn newest_entry_is_found_after_wrapping_around() {
+        let mut storage = MockStorage::default();
+        let (mut log, loaded) = WearLog::<Counters>::load(COUNTERS_REGION, &storage);
+        assert_eq!(loaded, None);
+
+        let entries = COUNTERS_REGION.entries as u32;
+        for startups in 1..=entries * 3 + 5 {
+            log.write(&counters(startups), &mut storage);
+        }
+
+        let (_, loaded) = WearLog::<Counters>::load(COUNTERS_REGION, &storage);
+        assert_eq!(loaded, Some(counters(entries * 3 + 5)));
+    }
+
+    #[test]
+    fn interrupted_write_keeps_the_previous_entry() {
+        let mut storage = MockStorage::default();
+        let (mut log, _) = WearLog::load(COUNTERS_REGION, &storage);
+        log.write(&counters(1), &mut storage);
+        log.write(&counters(2), &mut storage);
+
+        // Only half of the newest entry made it into the EEPROM.
+        let start = address::<Counters>(COUNTERS_REGION, 1) as usize;
+        let end = address::<Counters>(COUNTERS_REGION, 2) as usize;
+        storage.data[start + 6..end].fill(0xFF);
+
+        let (mut log, loaded) = WearLog::load(COUNTERS_REGION, &storage);
+        assert_eq!(loaded, Some(counters(1)));
+
+        log.write(&counters(2), &mut storage);
+        assert_eq!(
+            WearLog::<Counters>::load(COUNTERS_REGION, &storage).1,
+            Some(counters(2))
+        );
+    }
+}
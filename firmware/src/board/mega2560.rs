@@ -0,0 +1,103 @@
+//! The Arduino Mega 2560. The driver sits on D22 to D27 of the double row
+//! header, the end switches on D28 and D29. The status LED is the built-in
+//! one on D13. The UART of a TMC driver uses D30 and D31. The larger chip
+//! leaves room in the flash and the RAM for all features and has 4 kB of
+//! EEPROM and three more UARTs.
+
+use super::hal;
+use hal::hal::port::{PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7, PB7, PE0, PE1};
+use hal::port::mode::{Floating, Input, Output, PullUp};
+use hal::port::Pin;
+
+use super::{Board, DriverPins, EndSwitches, Pins};
+
+pub type StepPin = Pin<Output, PA0>;
+pub type DirPin = Pin<Output, PA1>;
+pub type EnablePin = Pin<Output, PA2>;
+pub type M0Pin = Pin<Output, PA3>;
+pub type M1Pin = Pin<Output, PA4>;
+pub type M2Pin = Pin<Output, PA5>;
+pub type ForwardLimitPin = Pin<Input<PullUp>, PA6>;
+pub type BackwardLimitPin = Pin<Input<PullUp>, PA7>;
+pub type LedPin = Pin<Output, PB7>;
+pub type RxPin = Pin<Input<Floating>, PE0>;
+pub type TxPin = Pin<Output, PE1>;
+#[cfg(feature = "tmc")]
+pub type DriverTxPin = Pin<Output, hal::hal::port::PC7>;
+#[cfg(feature = "tmc")]
+pub type DriverRxPin = Pin<Input<Floating>, hal::hal::port::PC6>;
+/// ADC0, the A0 pin.
+#[cfg(feature = "supply")]
+pub type SupplyPin = Pin<hal::port::mode::Analog, hal::hal::port::PF0>;
+
+/// The MUX bits of the 1.1 V bandgap reference, the noise source of `entropy`.
+/// MUX5 in ADCSRB stays cleared.
+#[cfg(feature = "bootloader")]
+pub const BANDGAP_CHANNEL: u8 = 0b1_1110;
+
+/// The word address of the boot section of 4096 words, BOOTSZ = 00. That is
+/// the high fuse 0xD8 the Mega ships with for its own bootloader, a smaller
+/// one like FastBoot has to be built for this address as well. It lies above
+/// 64k words, see the `bootloader` module.
+pub const BOOT_SECTION: u32 = 0x1_F000;
+
+/// The step pin PA0 and the enable pin PA2 of the driver.
+const STEP_BIT: u8 = 1 << 0;
+const ENABLE_BIT: u8 = 1 << 2;
+
+pub fn take(dp: hal::Peripherals) -> Board {
+    let pins = hal::pins!(dp);
+
+    #[cfg(feature = "supply")]
+    let mut adc = hal::Adc::new(dp.ADC, Default::default());
+    #[cfg(feature = "supply")]
+    let supply_pin = pins.a0.into_analog_input(&mut adc);
+
+    let driver = DriverPins::new(
+        pins.d23.into_output(),
+        pins.d24.into_output(),
+        (
+            pins.d25.into_output(),
+            pins.d26.into_output(),
+            pins.d27.into_output(),
+        ),
+    );
+
+    Board {
+        pins: Pins {
+            step: pins.d22.into_output(),
+            driver,
+            end_switches: EndSwitches::new(
+                pins.d28.into_pull_up_input(),
+                pins.d29.into_pull_up_input(),
+            ),
+            led: pins.d13.into_output(),
+            rx: pins.d0,
+            tx: pins.d1.into_output(),
+            #[cfg(feature = "tmc")]
+            driver_tx: pins.d30.into_output(),
+            #[cfg(feature = "tmc")]
+            driver_rx: pins.d31,
+        },
+        #[cfg(feature = "supply")]
+        adc,
+        #[cfg(feature = "supply")]
+        supply_pin,
+        cpu: dp.CPU,
+        eeprom: dp.EEPROM,
+        tc0: dp.TC0,
+        tc1: dp.TC1,
+        usart0: dp.USART0,
+        wdt: dp.WDT,
+    }
+}
+
+/// Pulls the step pin low and releases the driver by writing the port
+/// directly, for the panic handler.
+pub fn release_motor(dp: &hal::Peripherals) {
+    // SAFETY:
+    // Only the step and the enable pin are changed.
+    dp.PORTA
+        .porta
+        .modify(|r, w| unsafe { w.bits((r.bits() & !STEP_BIT) | ENABLE_BIT) });
+}
@@ -0,0 +1,62 @@
+//! The boards the firmware runs on. A board selects its board support package
+//! of `arduino-hal` and maps the logical functions to its pins: the step, direction and enable inputs of
+//! the driver, the microstep mode pins M0 to M2, the two end switches, the
+//! status LED, the supply divider and the UART of a TMC driver. The rest of the firmware only uses the
+//! names defined here. The direction, enable and mode pins and the end switches
+//! are handed to the `embedded-hal` based types of `eq_control::pins`.
+//!
+//! The board is selected with a Cargo feature, the Arduino Uno is the default.
+//! Both boards export the same items, so the modules must be kept in sync.
+
+#[cfg(all(feature = "board-uno", feature = "board-mega2560"))]
+compile_error!("Select only one board feature.");
+
+#[cfg(not(any(feature = "board-uno", feature = "board-mega2560")))]
+compile_error!("Select a board feature, e.g. `--features board-uno`.");
+
+pub use arduino_hal as hal;
+
+#[cfg(feature = "board-uno")]
+mod uno;
+#[cfg(feature = "board-uno")]
+pub use uno::*;
+
+#[cfg(feature = "board-mega2560")]
+mod mega2560;
+#[cfg(feature = "board-mega2560")]
+pub use mega2560::*;
+
+/// The direction, enable and microstep mode pins M0 to M2 of the driver.
+pub type DriverPins = eq_control::pins::DriverPins<DirPin, EnablePin, M0Pin, M1Pin, M2Pin>;
+/// The end switches close to ground, the forward one first.
+pub type EndSwitches = eq_control::pins::EndSwitches<ForwardLimitPin, BackwardLimitPin>;
+
+/// The pins of the board, already set to their modes.
+pub struct Pins {
+    pub step: StepPin,
+    pub driver: DriverPins,
+    pub end_switches: EndSwitches,
+    pub led: LedPin,
+    pub rx: RxPin,
+    pub tx: TxPin,
+    /// The single-wire UART of a TMC2208 or TMC2209, see `driver_uart`.
+    #[cfg(feature = "tmc")]
+    pub driver_tx: DriverTxPin,
+    #[cfg(feature = "tmc")]
+    pub driver_rx: DriverRxPin,
+}
+
+/// The peripherals after the ports were turned into the pins of the board.
+pub struct Board {
+    pub pins: Pins,
+    #[cfg(feature = "supply")]
+    pub adc: hal::Adc,
+    #[cfg(feature = "supply")]
+    pub supply_pin: SupplyPin,
+    pub cpu: hal::pac::CPU,
+    pub eeprom: hal::pac::EEPROM,
+    pub tc0: hal::pac::TC0,
+    pub tc1: hal::pac::TC1,
+    pub usart0: hal::pac::USART0,
+    pub wdt: hal::pac::WDT,
+}
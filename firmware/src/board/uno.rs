@@ -0,0 +1,99 @@
+//! The Arduino Uno and the bare Atmega328p. The driver sits on D8 to D13 and
+//! the end switches on D2 and D3. The built-in LED on D13 is the direction
+//! pin, so the status LED goes to D4. The UART of a TMC driver uses D6 and D7.
+
+use super::hal;
+use hal::hal::port::{PB0, PB1, PB2, PB3, PB4, PB5, PD0, PD1, PD2, PD3, PD4};
+use hal::port::mode::{Floating, Input, Output, PullUp};
+use hal::port::Pin;
+
+use super::{Board, DriverPins, EndSwitches, Pins};
+
+pub type StepPin = Pin<Output, PB0>;
+pub type DirPin = Pin<Output, PB5>;
+pub type EnablePin = Pin<Output, PB4>;
+pub type M0Pin = Pin<Output, PB1>;
+pub type M1Pin = Pin<Output, PB2>;
+pub type M2Pin = Pin<Output, PB3>;
+pub type ForwardLimitPin = Pin<Input<PullUp>, PD2>;
+pub type BackwardLimitPin = Pin<Input<PullUp>, PD3>;
+pub type LedPin = Pin<Output, PD4>;
+pub type RxPin = Pin<Input<Floating>, PD0>;
+pub type TxPin = Pin<Output, PD1>;
+#[cfg(feature = "tmc")]
+pub type DriverTxPin = Pin<Output, hal::hal::port::PD6>;
+#[cfg(feature = "tmc")]
+pub type DriverRxPin = Pin<Input<Floating>, hal::hal::port::PD7>;
+/// ADC0, the A0 pin.
+#[cfg(feature = "supply")]
+pub type SupplyPin = Pin<hal::port::mode::Analog, hal::hal::port::PC0>;
+
+/// The MUX bits of the 1.1 V bandgap reference, the noise source of `entropy`.
+#[cfg(feature = "bootloader")]
+pub const BANDGAP_CHANNEL: u8 = 0b1110;
+
+/// The word address of the boot section of 256 words, BOOTSZ = 11. That is
+/// the high fuse 0xDE the Uno ships with for Optiboot, large enough for
+/// FastBoot as well.
+pub const BOOT_SECTION: u32 = 0x3F00;
+
+/// The step pin PB0 and the enable pin PB4 of the driver.
+const STEP_BIT: u8 = 1 << 0;
+const ENABLE_BIT: u8 = 1 << 4;
+
+pub fn take(dp: hal::Peripherals) -> Board {
+    let pins = hal::pins!(dp);
+
+    #[cfg(feature = "supply")]
+    let mut adc = hal::Adc::new(dp.ADC, Default::default());
+    #[cfg(feature = "supply")]
+    let supply_pin = pins.a0.into_analog_input(&mut adc);
+
+    let driver = DriverPins::new(
+        pins.d13.into_output(),
+        pins.d12.into_output(),
+        (
+            pins.d9.into_output(),
+            pins.d10.into_output(),
+            pins.d11.into_output(),
+        ),
+    );
+
+    Board {
+        pins: Pins {
+            step: pins.d8.into_output(),
+            driver,
+            end_switches: EndSwitches::new(
+                pins.d2.into_pull_up_input(),
+                pins.d3.into_pull_up_input(),
+            ),
+            led: pins.d4.into_output(),
+            rx: pins.d0,
+            tx: pins.d1.into_output(),
+            #[cfg(feature = "tmc")]
+            driver_tx: pins.d6.into_output(),
+            #[cfg(feature = "tmc")]
+            driver_rx: pins.d7,
+        },
+        #[cfg(feature = "supply")]
+        adc,
+        #[cfg(feature = "supply")]
+        supply_pin,
+        cpu: dp.CPU,
+        eeprom: dp.EEPROM,
+        tc0: dp.TC0,
+        tc1: dp.TC1,
+        usart0: dp.USART0,
+        wdt: dp.WDT,
+    }
+}
+
+/// Pulls the step pin low and releases the driver by writing the port
+/// directly, for the panic handler.
+pub fn release_motor(dp: &hal::Peripherals) {
+    // SAFETY:
+    // Only the step and the enable pin are changed.
+    dp.PORTB
+        .portb
+        .modify(|r, w| unsafe { w.bits((r.bits() & !STEP_BIT) | ENABLE_BIT) });
+}
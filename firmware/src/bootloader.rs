@@ -0,0 +1,47 @@
+//! Starts the bootloader for a firmware update over the serial port, so the
+//! reset button does not have to be reached on the platform. The controller
+//! has already stopped the motor and waited for the EEPROM writes.
+//!
+//! The jump goes straight to the start of the boot section, where FastBoot
+//! waits for the host as after a reset. It starts the firmware again when the
+//! host does not answer in time.
+//!
+//! A function pointer holds 16 bits of a word address. The boot section of
+//! the Atmega2560 lies above that, the EIND register supplies the upper bits
+//! of the indirect jump there.
+
+use crate::board::{hal, BOOT_SECTION};
+
+/// EIND in the data address space of the Atmega2560.
+#[cfg(feature = "board-mega2560")]
+const EIND: *mut u8 = 0x5C as *mut u8;
+
+/// The watchdog has to be stopped before, the bootloader does not feed it.
+pub fn start() -> ! {
+    avr_device::interrupt::disable();
+
+    // SAFETY:
+    // The interrupts are off and this function never returns,
+    // so nothing else will access the peripherals anymore.
+    let dp = unsafe { hal::Peripherals::steal() };
+
+    // The bootloader does not know the step timer, it must not fire anymore.
+    dp.TC1.timsk1.reset();
+    dp.TC1.tccr1b.reset();
+    // Let the firmware see no reset flag when the bootloader starts it again.
+    dp.CPU.mcusr.reset();
+
+    // SAFETY:
+    // EIND is only used by indirect jumps and calls, the next one is the last.
+    #[cfg(feature = "board-mega2560")]
+    unsafe {
+        core::ptr::write_volatile(EIND, (BOOT_SECTION >> 16) as u8);
+    }
+
+    // SAFETY:
+    // The boot section holds the bootloader, which expects to be entered
+    // with the interrupts disabled like after a reset.
+    let bootloader: extern "C" fn() -> ! =
+        unsafe { core::mem::transmute(BOOT_SECTION as u16 as usize) };
+    bootloader()
+}
@@ -0,0 +1,47 @@
+//! The 8-bit timer 0 is used as a monotonic millisecond clock.
+//! With a prescaler of 64 the timer counts with 250 kHz. In the Clear
+//! Timer on Compare mode with a compare value of 249, the ISR is called
+//! exactly every millisecond.
+
+use core::cell::Cell;
+
+use avr_device::interrupt::Mutex;
+use eq_control::hardware::Clock;
+
+const PRESCALER_TICKS: u8 = 250;
+
+static MILLIS_COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+pub struct Millis {
+    _tc0: crate::board::hal::pac::TC0,
+}
+
+impl Millis {
+    pub fn new(tc0: crate::board::hal::pac::TC0) -> Self {
+        // Timer Configuration:
+        // - WGM = 2: CTC mode (Clear Timer on Compare Match)
+        // - Prescaler 64
+        // - OCR0A = 249
+        tc0.tccr0a.write(|w| w.wgm0().ctc());
+        tc0.ocr0a.write(|w| unsafe { w.bits(PRESCALER_TICKS - 1) });
+        tc0.tccr0b.write(|w| w.cs0().prescale_64());
+        tc0.timsk0.write(|w| w.ocie0a().set_bit());
+
+        Self { _tc0: tc0 }
+    }
+}
+
+impl Clock for Millis {
+    fn millis(&self) -> u32 {
+        avr_device::interrupt::free(|cs| MILLIS_COUNTER.borrow(cs).get())
+    }
+}
+
+#[cfg_attr(feature = "board-uno", avr_device::interrupt(atmega328p))]
+#[cfg_attr(feature = "board-mega2560", avr_device::interrupt(atmega2560))]
+fn TIMER0_COMPA() {
+    avr_device::interrupt::free(|cs| {
+        let counter = MILLIS_COUNTER.borrow(cs);
+        counter.set(counter.get().wrapping_add(1));
+    });
+}
@@ -0,0 +1,128 @@
+//! A bit-banged UART for the single-wire interface of the TMC2208 and TMC2209,
+//! the hardware USART is taken by the host. The TX pin drives PDN_UART through
+//! 1 kΩ and the RX pin is connected to it directly, so the driver can pull the
+//! line against TX while it answers.
+//!
+//! The bits are timed by busy waiting with the interrupts disabled, an interrupt
+//! within a byte would stretch its bits. The host USART buffers two bytes and
+//! shifts in a third one, at 57600 baud it overruns when its interrupt is held
+//! off for about 350 µs. So the driver runs at 57600 baud as well: a byte is
+//! sent in about 175 µs, and a byte is received in at most 240 µs with the
+//! wait for its start bit. The interrupts are enabled in between.
+//!
+//! The datagrams themselves are built in `eq_control::tmc`. The pins and the
+//! delay only need the `embedded-hal` traits, the board picks them.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use eq_control::hardware::DriverUart;
+
+/// One bit at 57600 baud, 17.4 µs. The driver detects the baud rate from the
+/// sync nibble of every datagram, so a few percent off do not matter.
+const BIT_US: u32 = 17;
+/// The driver answers after 8 bit times, 4 more bytes are plenty.
+const REPLY_TIMEOUT_BITS: u32 = 48;
+/// The bytes of the reply follow each other without a gap.
+const BYTE_TIMEOUT_BITS: u32 = 4;
+/// The start bit is awaited in windows of this many bits with the interrupts
+/// disabled. Only an interrupt run between two windows can delay the sampling.
+const WINDOW_BITS: u32 = 4;
+
+/// The pins of the AVR can not fail, their errors are ignored. A line that
+/// can not be read never shows a start bit, so the read times out.
+pub struct SoftUart<Tx, Rx, D> {
+    tx: Tx,
+    rx: Rx,
+    delay: D,
+}
+
+impl<Tx: OutputPin, Rx: InputPin, D: DelayNs> SoftUart<Tx, Rx, D> {
+    pub fn new(mut tx: Tx, rx: Rx, delay: D) -> Self {
+        // The line idles high.
+        tx.set_high().ok();
+        Self { tx, rx, delay }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let tx = &mut self.tx;
+        let delay = &mut self.delay;
+        avr_device::interrupt::free(|_| {
+            tx.set_low().ok();
+            delay.delay_us(BIT_US);
+            for bit in 0..8 {
+                if byte >> bit & 1 != 0 {
+                    tx.set_high().ok();
+                } else {
+                    tx.set_low().ok();
+                }
+                delay.delay_us(BIT_US);
+            }
+            tx.set_high().ok();
+            delay.delay_us(BIT_US);
+        });
+    }
+
+    /// A byte misread because an interrupt delayed the start bit fails the
+    /// CRC of the reply, so the read of the register fails.
+    fn read_byte(&mut self, timeout_bits: u32) -> Option<u8> {
+        let mut remaining_bits = timeout_bits;
+        while remaining_bits > 0 {
+            let window_bits = remaining_bits.min(WINDOW_BITS);
+            remaining_bits -= window_bits;
+
+            let rx = &mut self.rx;
+            let delay = &mut self.delay;
+            let received = avr_device::interrupt::free(|_| {
+                // Polled every microsecond.
+                let mut polls = window_bits * BIT_US;
+                while rx.is_high().unwrap_or(true) {
+                    if polls == 0 {
+                        return None;
+                    }
+                    polls -= 1;
+                    delay.delay_us(1);
+                }
+
+                // To the middle of the first data bit.
+                delay.delay_us(BIT_US + BIT_US / 2);
+                let mut byte = 0_u8;
+                for bit in 0..8 {
+                    if rx.is_high().unwrap_or(true) {
+                        byte |= 1 << bit;
+                    }
+                    delay.delay_us(BIT_US);
+                }
+                // The middle of the stop bit, which has to be high.
+                Some(rx.is_high().unwrap_or(false).then(|| byte))
+            });
+
+            if let Some(byte) = received {
+                return byte;
+            }
+        }
+        None
+    }
+}
+
+impl<Tx: OutputPin, Rx: InputPin, D: DelayNs> DriverUart for SoftUart<Tx, Rx, D> {
+    fn send(&mut self, datagram: &[u8]) {
+        for byte in datagram {
+            self.write_byte(*byte);
+        }
+    }
+
+    fn transfer(&mut self, request: &[u8], reply: &mut [u8]) -> bool {
+        // RX sees the request as well, it is over before the reply is awaited.
+        self.send(request);
+
+        let mut timeout_bits = REPLY_TIMEOUT_BITS;
+        for byte in reply.iter_mut() {
+            match self.read_byte(timeout_bits) {
+                Some(received) => *byte = received,
+                None => return false,
+            }
+            timeout_bits = BYTE_TIMEOUT_BITS;
+        }
+        true
+    }
+}
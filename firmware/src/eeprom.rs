@@ -1,87 +1,174 @@
-//! This module handles all the necessary EEPROM functionality.
-//! The EEPROM will hold the last velocity set by UART,
-//! so it can be automatically loaded on startup.
-//! It also holds the number of runtimes of the program.
-//! The Atmega328p chip has a word size of 8 Bit.
-
-use atmega328p_hal as hal;
-use embedded_time::duration::*;
-use hal::pac::EEPROM;
+//! The EEPROM storage of the AVR. The layout of
+//! the stored values is defined in the control crate.
+//! The Atmega328p and the Atmega2560 have a word size of 8 Bit.
+//!
+//! Writing a single byte takes about 3.3 ms. So the writes are not done
+//! right away but put into a queue, which is drained by the EE_READY
+//! interrupt one byte at a time. The main loop keeps running in the
+//! meantime and polls `is_busy` to learn when the data has arrived.
+//! The queue keeps the order of the writes, which the double-buffered
+//! records of the control crate rely on. Reads see the queued data.
+
+use core::cell::RefCell;
+
+use crate::board::hal::pac::EEPROM;
+use avr_device::interrupt::{CriticalSection, Mutex};
+use eq_control::hardware::Storage;
+
+/// A whole buffer slot of 48 bytes, so a profile with its name and the
+/// generation byte is queued at once. Longer writes wait for free entries.
+const QUEUE_LENGTH: usize = 48;
+
+/// The bytes waiting to be written, the oldest one at `head`.
+struct WriteQueue {
+    addresses: [u16; QUEUE_LENGTH],
+    words: [u8; QUEUE_LENGTH],
+    head: usize,
+    length: usize,
+}
 
-const BASE_ADDR_STARTUPS: u16 = 0x0000;
-const BASE_ADDR_TIME: u16 = 0x00F0;
+impl WriteQueue {
+    fn push(&mut self, address: u16, word: u8) -> bool {
+        if self.length == QUEUE_LENGTH {
+            return false;
+        }
+
+        let index = (self.head + self.length) % QUEUE_LENGTH;
+        self.addresses[index] = address;
+        self.words[index] = word;
+        self.length += 1;
+        true
+    }
 
-pub fn read_waiting_time(eeprom_registers: &EEPROM) -> Microseconds {
-    let mut time = [0 as u8; 4];
+    fn pop(&mut self) -> Option<(u16, u8)> {
+        if self.length == 0 {
+            return None;
+        }
 
-    for (i, word) in time.iter_mut().enumerate() {
-        *word = read_word(BASE_ADDR_TIME + i as u16, eeprom_registers);
+        let entry = (self.addresses[self.head], self.words[self.head]);
+        self.head = (self.head + 1) % QUEUE_LENGTH;
+        self.length -= 1;
+        Some(entry)
     }
 
-    Microseconds(u8_to_u32(time))
+    /// The newest queued word for the address, if there is one.
+    fn pending(&self, address: u16) -> Option<u8> {
+        (0..self.length)
+            .rev()
+            .map(|i| (self.head + i) % QUEUE_LENGTH)
+            .find(|index| self.addresses[*index] == address)
+            .map(|index| self.words[index])
+    }
 }
 
-pub fn write_waiting_time(time: Microseconds, eeprom_registers: &EEPROM) {
-    let time = time.integer().to_be_bytes();
+/// The registers live here as well, so the ISR can reach them.
+struct EepromState {
+    registers: EEPROM,
+    queue: WriteQueue,
+}
 
-    for (i, word) in time.iter().enumerate() {
-        write_word(*word, BASE_ADDR_TIME + i as u16, eeprom_registers);
+static EEPROM_STATE: Mutex<RefCell<Option<EepromState>>> = Mutex::new(RefCell::new(None));
+
+pub struct Eeprom;
+
+impl Eeprom {
+    pub fn new(registers: EEPROM) -> Self {
+        avr_device::interrupt::free(|cs| {
+            EEPROM_STATE.borrow(cs).replace(Some(EepromState {
+                registers,
+                queue: WriteQueue {
+                    addresses: [0; QUEUE_LENGTH],
+                    words: [0; QUEUE_LENGTH],
+                    head: 0,
+                    length: 0,
+                },
+            }));
+        });
+        Self
     }
 }
 
-pub fn increment_startups(eeprom_registers: &EEPROM) {
-    let mut startups = read_startups(eeprom_registers);
-
-    // Explicit overflow
-    startups.overflowing_add(1);
+impl Storage for Eeprom {
+    fn read(&self, address: u16, buffer: &mut [u8]) {
+        for (i, word) in buffer.iter_mut().enumerate() {
+            // The cells can not be read while a write is running, so
+            // retry instead of blocking the other interrupts meanwhile.
+            loop {
+                if let Some(read) = avr_device::interrupt::free(|cs| {
+                    with_state(cs, |state| read_word(address + i as u16, state))
+                }) {
+                    *word = read;
+                    break;
+                }
+            }
+        }
+    }
 
-    let startups = startups.to_be_bytes();
+    fn write(&mut self, address: u16, data: &[u8]) {
+        for (i, word) in data.iter().enumerate() {
+            // When the queue is full, wait for the ISR to make room.
+            while !avr_device::interrupt::free(|cs| {
+                with_state(cs, |state| {
+                    let queued = state.queue.push(address + i as u16, *word);
+                    // Enable the EE_READY interrupt, it drains the queue.
+                    state.registers.eecr.modify(|_, w| w.eerie().set_bit());
+                    Some(queued)
+                })
+                .unwrap_or(true)
+            }) {}
+        }
+    }
 
-    for (i, word) in startups.iter().enumerate() {
-        write_word(*word, BASE_ADDR_STARTUPS + i as u16, eeprom_registers);
+    fn is_busy(&self) -> bool {
+        avr_device::interrupt::free(|cs| {
+            with_state(cs, |state| {
+                Some(state.queue.length > 0 || state.registers.eecr.read().eepe().bit_is_set())
+            })
+            .unwrap_or(false)
+        })
     }
 }
 
-pub fn read_startups(eeprom_registers: &EEPROM) -> u32 {
-    let mut startups = [0 as u8; 4];
+fn with_state<T>(cs: &CriticalSection, f: impl FnOnce(&mut EepromState) -> Option<T>) -> Option<T> {
+    EEPROM_STATE.borrow(cs).borrow_mut().as_mut().and_then(f)
+}
 
-    for (i, word) in startups.iter_mut().enumerate() {
-        *word = read_word(BASE_ADDR_STARTUPS + i as u16, eeprom_registers);
+/// Returns `None` while a write is running. A queued word is returned
+/// instead of the cell, it will be there soon.
+fn read_word(address: u16, state: &mut EepromState) -> Option<u8> {
+    if let Some(word) = state.queue.pending(address) {
+        return Some(word);
     }
 
-    u8_to_u32(startups)
-}
-
-fn read_word(address: u16, eeprom_registers: &EEPROM) -> u8 {
-    while eeprom_registers.eecr.read().eepe().bit_is_set() {}
+    let eeprom_registers = &state.registers;
+    if eeprom_registers.eecr.read().eepe().bit_is_set() {
+        return None;
+    }
 
     // Write address
     // SAFETY:
-    // No other interrupt will access this register in any way
-    // so it is safe to write raw bits into it.
+    // The registers are only accessed within a critical
+    // section so it is safe to write raw bits into it.
     eeprom_registers.eear.write(|w| unsafe { w.bits(address) });
 
     // Enable read operation
-    eeprom_registers.eecr.write(|w| w.eere().set_bit());
+    eeprom_registers.eecr.modify(|_, w| w.eere().set_bit());
 
     // Read the bits from the register
-    eeprom_registers.eedr.read().bits()
+    Some(eeprom_registers.eedr.read().bits())
 }
 
 fn write_word(word: u8, address: u16, eeprom_registers: &EEPROM) {
-    // Wait until writing is possible.
-    while eeprom_registers.eecr.read().eepe().bit_is_set() {}
-
     // Write address
     // SAFETY:
-    // No other interrupt will access this register in any way
-    // so it is safe to write raw bits into it.
+    // The registers are only accessed within a critical
+    // section so it is safe to write raw bits into it.
     eeprom_registers.eear.write(|w| unsafe { w.bits(address) });
 
     // Write data
     // SAFETY:
-    // No other interrupt will access this register in any way
-    // to it is safe to write raw bits into it.
+    // The registers are only accessed within a critical
+    // section so it is safe to write raw bits into it.
     eeprom_registers.eedr.write(|w| unsafe { w.bits(word) });
 
     // Enable master write
@@ -90,18 +177,27 @@ fn write_word(word: u8, address: u16, eeprom_registers: &EEPROM) {
     eeprom_registers.eecr.modify(|_, w| w.eepe().set_bit());
 }
 
-fn u8_to_u32(number_array: [u8; 4]) -> u32 {
-    let mut result;
-
-    result = (number_array[0] as u32) << 24;
-    result += (number_array[1] as u32) << 16;
-    result += (number_array[2] as u32) << 8;
-    result += number_array[3] as u32;
-
-    result
+/// Writes right away and waits for every byte. Only for the panic handler:
+/// the interrupts are off, so the queue would never be drained.
+pub fn write_blocking(address: u16, data: &[u8], eeprom_registers: &EEPROM) {
+    for (i, word) in data.iter().enumerate() {
+        while eeprom_registers.eecr.read().eepe().bit_is_set() {}
+        write_word(*word, address + i as u16, eeprom_registers);
+    }
 }
 
-#[test]
-fn test_conversion() {
-    assert_eq!(u8_to_u32([0, 0, 0, 0]), 0);
-}
\ No newline at end of file
+/// Called whenever the EEPROM is ready for the next write.
+#[cfg_attr(feature = "board-uno", avr_device::interrupt(atmega328p))]
+#[cfg_attr(feature = "board-mega2560", avr_device::interrupt(atmega2560))]
+fn EE_READY() {
+    avr_device::interrupt::free(|cs| {
+        with_state(cs, |state| {
+            match state.queue.pop() {
+                Some((address, word)) => write_word(word, address, &state.registers),
+                // Nothing left, the interrupt would fire all the time otherwise.
+                None => state.registers.eecr.modify(|_, w| w.eerie().clear_bit()),
+            }
+            Some(())
+        });
+    });
+}
@@ -0,0 +1,36 @@
+//! A random seed for the bootloader challenges, taken from the noise of the ADC
+//! before the board takes it over. The bandgap reference is converted against
+//! AVcc with the ADC clock far too fast for an exact result, so the lower bits
+//! of the readings flicker. The seed does not have to be perfect,
+//! `eq_control::auth` chains it through the keyed hash with the startup counter.
+
+use crate::board::{hal, BANDGAP_CHANNEL};
+
+/// AVcc as the reference.
+const REFS0: u8 = 1 << 6;
+const ADEN: u8 = 1 << 7;
+const ADSC: u8 = 1 << 6;
+/// The ADC clock at a half of the CPU clock.
+const PRESCALER_2: u8 = 0b001;
+/// Every reading is folded into the seed at another position.
+const CONVERSIONS: u32 = 128;
+
+pub fn seed(adc: &hal::pac::ADC) -> u64 {
+    // SAFETY:
+    // The ADC is not used by anything else yet, every value is valid here.
+    adc.admux
+        .write(|w| unsafe { w.bits(REFS0 | BANDGAP_CHANNEL) });
+
+    let mut seed = 0_u64;
+    for _ in 0..CONVERSIONS {
+        adc.adcsra
+            .write(|w| unsafe { w.bits(ADEN | ADSC | PRESCALER_2) });
+        while adc.adcsra.read().bits() & ADSC != 0 {}
+        seed = seed.rotate_left(7) ^ adc.adc.read().bits() as u64;
+    }
+
+    // The supply monitor sets the ADC up again as it needs it.
+    adc.adcsra.reset();
+    adc.admux.reset();
+    seed
+}
@@ -5,9 +5,19 @@
 // ===========================================================================
 // Modules
 // ===========================================================================
+mod board;
+#[cfg(feature = "bootloader")]
+mod bootloader;
+mod clock;
+#[cfg(feature = "tmc")]
+mod driver_uart;
 mod eeprom;
+#[cfg(feature = "bootloader")]
+mod entropy;
+mod panic;
 mod serial;
-mod state_machine;
+#[cfg(feature = "supply")]
+mod supply;
 mod timer;
 
 // ===========================================================================
@@ -15,49 +25,63 @@ mod timer;
 // ===========================================================================
 use core::cell::RefCell;
 
-use atmega328p_hal as hal;
 use avr_device::interrupt::Mutex;
-use hal::clock::MHz16;
-use hal::port::mode::*;
-use hal::port::*;
-use hal::prelude::*;
-use hal::usart::*;
-use hal::wdt::*;
+use board::hal;
+use hal::hal::wdt::{Timeout, Wdt};
+use hal::usart::{UsartReader, UsartWriter};
 
-use embedded_time::duration::*;
-use staticvec::StaticString;
-
-use panic_halt as _;
-
-use crate::serial::InputVariant;
-use crate::state_machine::*;
+#[cfg(feature = "bootloader")]
+use eq_control::auth;
+use eq_control::hardware::{Hardware, RxError};
+use eq_control::reset::ResetCause;
+use eq_control::ring_buffer::RingBuffer;
+use eq_control::{Control, Controller};
 
 // ===========================================================================
 // Types
 // ===========================================================================
 
 /// Type definition for the USART0 reader
-type Usart0Reader =
-    UsartReader<hal::pac::USART0, portd::PD0<Input<Floating>>, portd::PD1<Output>, MHz16>;
-type Usart0Writer =
-    UsartWriter<hal::pac::USART0, portd::PD0<Input<Floating>>, portd::PD1<Output>, MHz16>;
+type Usart0Reader = UsartReader<hal::pac::USART0, board::RxPin, board::TxPin>;
+type Usart0Writer = UsartWriter<hal::pac::USART0, board::RxPin, board::TxPin>;
 
 // ===========================================================================
 // Structs
 // ===========================================================================
 
-/// Serial Buffer object that holds the buffer and the receiver side of the usart.
-struct SerialBuffer {
-    usart0_rx: Usart0Reader,
-    buffer: StaticString<64>,
-    is_complete: bool,
+/// The hardware of the platform the control logic runs on.
+struct Platform;
+
+impl Hardware for Platform {
+    type Motor = timer::Motor;
+    type Storage = eeprom::Eeprom;
+    type Serial = serial::Usart;
+    type Clock = clock::Millis;
+    #[cfg(feature = "supply")]
+    type Supply = supply::Supply;
+
+    /// Set `EQ_BOOTLOADER_KEY` to 32 hex digits when building. There is no
+    /// default, a key everybody knows would let anybody flash the chip.
+    #[cfg(feature = "bootloader")]
+    const BOOTLOADER_KEY: auth::Key = auth::parse_key(env!(
+        "EQ_BOOTLOADER_KEY",
+        "The bootloader feature needs a key: set EQ_BOOTLOADER_KEY to 32 hex digits."
+    ));
 }
 
 /// Timer struct that hold the timer register (it has to be altered in an ISR)
 /// and the corresponding timer pin which is conrtolled by the timer.
+/// The other pins of the driver live here as well, so the ISR knows in which
+/// direction the position has to be counted. So do the end switches and the LED.
 struct TimerStructure {
-    pin: portb::PB0<Output>,
+    pin: board::StepPin,
     pin_is_high: bool,
+    driver: board::DriverPins,
+    end_switches: board::EndSwitches,
+    /// A closed end switch held back a step, the main loop clears it.
+    limit_hit: bool,
+    led_pin: board::LedPin,
+    position: i32,
     tc1: hal::pac::TC1,
 }
 
@@ -67,50 +91,63 @@ struct TimerStructure {
 
 /// The step pin controls the motor.
 static TIMER_STRUCTURE: Mutex<RefCell<Option<TimerStructure>>> = Mutex::new(RefCell::new(None));
-/// The USART buffer object that will be used in the ISR to store the received bytes.
-static SERIAL_BUFFER: Mutex<RefCell<Option<SerialBuffer>>> = Mutex::new(RefCell::new(None));
+/// The receiver side of the USART, only used by its ISR.
+static USART0_READER: Mutex<RefCell<Option<Usart0Reader>>> = Mutex::new(RefCell::new(None));
+/// The received bytes on their way from the ISR to the main loop, which
+/// assembles and parses the lines.
+static RX_BUFFER: RingBuffer<Result<u8, RxError>, 64> = RingBuffer::new();
 
-#[atmega328p_hal::entry]
+#[hal::entry]
 fn main() -> ! {
-    let dp = hal::pac::Peripherals::take().unwrap();
+    let dp = hal::Peripherals::take().unwrap();
+    let reset_cause = read_reset_cause(&dp.CPU.mcusr);
+    #[cfg(feature = "bootloader")]
+    let seed = entropy::seed(&dp.ADC);
 
-    let mut portb = dp.PORTB.split();
-    let portd = dp.PORTD.split();
+    // The board splits the ports into the pins of the driver,
+    // the end switches and the LED.
+    let board = board::take(dp);
+    let pins = board.pins;
 
-    let step_pin = portb.pb0.into_output(&mut portb.ddr);
-    let mut dir_pin = portb.pb5.into_output(&mut portb.ddr);
+    // A watchdog reset, as by the reset command or the panic handler, leaves
+    // the watchdog running with its shortest timeout of 16 ms. It gets the
+    // long one before anything slow happens, or the chip keeps resetting.
+    let mut watchdog = Wdt::new(board.wdt, &board.cpu.mcusr);
+    watchdog.start(Timeout::Ms500).unwrap();
 
-    // 1/32 steps
-    let mut m0_pin = portb.pb1.into_output(&mut portb.ddr);
-    let mut m1_pin = portb.pb2.into_output(&mut portb.ddr);
-    let mut m2_pin = portb.pb3.into_output(&mut portb.ddr);
-    m0_pin.set_high();
-    m1_pin.set_high();
-    m2_pin.set_high();
+    let eeprom = eeprom::Eeprom::new(board.eeprom);
 
-    let tc1 = dp.TC1;
-    let eeprom_registers = dp.EEPROM;
+    // The battery is measured on ADC0.
+    #[cfg(feature = "supply")]
+    let supply = supply::Supply::new(board.adc, board.supply_pin);
 
     // Initialize the serial communication
-    let mut serial_handler = serial::SerialHandler::new(dp.USART0, portd);
-
-    eeprom::increment_startups(&eeprom_registers);
-
-    // Get the last waiting time from eeprom
-    let waiting_time = eeprom::read_waiting_time(&eeprom_registers);
+    let usart = serial::Usart::new(board.usart0, pins.rx, pins.tx);
 
     avr_device::interrupt::free(|cs| {
         TIMER_STRUCTURE.borrow(cs).replace(Some(TimerStructure {
-            pin: step_pin,
+            pin: pins.step,
             pin_is_high: false,
-            tc1,
+            driver: pins.driver,
+            end_switches: pins.end_switches,
+            limit_hit: false,
+            led_pin: pins.led,
+            position: 0,
+            tc1: board.tc1,
         }));
     });
 
-    // Initialize timer
+    // A TMC driver gets its settings over the UART on the first poll.
+    #[cfg(feature = "tmc")]
+    let motor = timer::Motor {
+        uart: driver_uart::SoftUart::new(pins.driver_tx, pins.driver_rx, hal::Delay::new()),
+    };
+    #[cfg(not(feature = "tmc"))]
+    let motor = timer::Motor {};
+
+    // Initialize timers
     timer::init();
-    timer::set_duration(waiting_time);
-    timer::set_timer_status(true);
+    let clock = clock::Millis::new(board.tc0);
 
     // SAFETY:
     // We are not in a critical section, so enabling interrupts is fine.
@@ -119,87 +156,64 @@ fn main() -> ! {
         avr_device::interrupt::enable();
     }
 
-    // Create the state machine
-    let mut eq_tracker = state_machine::EQTracker::new(waiting_time);
-
-    // The state machine is per default in the Tracking state, so we
-    // also want to enable the positive direction pin.
-    dir_pin.set_high().void_unwrap();
-
-    // Initialize a watchdog
-    let mut watchdog = Wdt::new(&dp.CPU.mcusr, dp.WDT);
-    watchdog.start(Timeout::Ms500);
+    // The controller loads the settings from the eeprom and
+    // starts as configured for power-up, or stays parked.
+    #[cfg(feature = "supply")]
+    let mut controller = Controller::<Platform>::new(
+        motor,
+        eeprom,
+        usart,
+        clock,
+        supply,
+        reset_cause,
+        #[cfg(feature = "bootloader")]
+        seed,
+    );
+    #[cfg(not(feature = "supply"))]
+    let mut controller = Controller::<Platform>::new(
+        motor,
+        eeprom,
+        usart,
+        clock,
+        reset_cause,
+        #[cfg(feature = "bootloader")]
+        seed,
+    );
 
     loop {
-        let input = serial_handler.handle_input();
-
-        match input {
-            Some(InputVariant::Track) => {
-                serial_handler.write_str("Track!\n");
-                eq_tracker.set_state(State::Track);
-                timer::set_duration(eq_tracker.get_waiting_time());
-                timer::set_timer_status(true);
-                dir_pin.set_high().void_unwrap();
-            }
-
-            Some(InputVariant::TrackNewTime(duration)) => {
-                serial_handler.write_str("Track with new duration: ");
-                serial_handler.write_number(*duration.integer());
-                serial_handler.write_str("us\n");
-                eq_tracker.set_state(State::Track);
-                eq_tracker.set_waiting_time(duration);
-                timer::set_duration(duration);
-                timer::set_timer_status(true);
-                dir_pin.set_high().void_unwrap();
+        match controller.poll() {
+            Control::Continue => {}
+            // Let the watchdog starve.
+            Control::Reset => loop {},
+            #[cfg(feature = "bootloader")]
+            Control::Bootloader => {
+                watchdog.stop();
+                bootloader::start();
             }
-
-            Some(InputVariant::Hold) => {
-                serial_handler.write_str("Hold Hold Hold!\n");
-                eq_tracker.set_state(State::Hold);
-                timer::set_timer_status(false);
-            }
-
-            Some(InputVariant::FastForward(direction)) => {
-                serial_handler.write_str("Fast Forward Mode!\n");
-                eq_tracker.set_state(State::FastForward(direction));
-                timer::set_duration(Microseconds(1200));
-                timer::set_timer_status(true);
-                if direction {
-                    dir_pin.set_high().void_unwrap();
-                } else {
-                    dir_pin.set_low().void_unwrap();
-                }
-            }
-
-            Some(InputVariant::SetDefault) => {
-                serial_handler.write_str("Write Default Value!\n");
-                eeprom::write_waiting_time(eq_tracker.get_waiting_time(), &eeprom_registers);
-            }
-
-            Some(InputVariant::Status) => serial_handler.send_status(
-                *eq_tracker.get_waiting_time().integer(),
-                *eeprom::read_waiting_time(&eeprom_registers).integer(),
-                eeprom::read_startups(&eeprom_registers),
-            ),
-
-            Some(InputVariant::BinaryStatus) => serial_handler.send_binary_status(
-                *eq_tracker.get_waiting_time().integer(),
-                *eeprom::read_waiting_time(&eeprom_registers).integer(),
-                eeprom::read_startups(&eeprom_registers),
-            ),
-
-            Some(InputVariant::Invalid) => serial_handler.write_str("Invalid operation!\n"),
-
-            Some(InputVariant::Reset) => {
-                serial_handler.write_str("Reset!\n");
-                // Let the watchdog starve.
-                loop {}
-            }
-
-            None => (),
         }
 
         // Feed the watchdog
         watchdog.feed();
     }
 }
+
+/// Reads why the chip restarted and clears the flags for the next reset.
+/// A power-on sets the other flags as well, so it is checked first.
+/// A bootloader that clears the flags itself makes every reset look unknown.
+fn read_reset_cause(mcusr: &hal::pac::cpu::MCUSR) -> ResetCause {
+    let flags = mcusr.read();
+    let reset_cause = if flags.porf().bit_is_set() {
+        ResetCause::PowerOn
+    } else if flags.borf().bit_is_set() {
+        ResetCause::BrownOut
+    } else if flags.wdrf().bit_is_set() {
+        ResetCause::Watchdog
+    } else if flags.extrf().bit_is_set() {
+        ResetCause::External
+    } else {
+        ResetCause::Unknown
+    };
+
+    mcusr.reset();
+    reset_cause
+}
@@ -0,0 +1,69 @@
+//! The panic handler. A panic must not leave the motor running wherever the
+//! step timer happened to be. So the timer is stopped and the driver released,
+//! then the location is written to the EEPROM and the UART and the watchdog
+//! resets the chip. The next boot counts the reset as a panic.
+//!
+//! Nothing of the regular drivers is used here, they may be the very thing
+//! that panicked. The registers are accessed directly instead.
+
+use core::convert::Infallible;
+use core::panic::PanicInfo;
+
+use crate::board::{self, hal};
+use crate::eeprom;
+use hal::pac::USART0;
+
+/// WDCE and WDE start the timed sequence, WDE alone with
+/// a prescaler of zero then resets after about 16 ms.
+const WDT_CHANGE_ENABLE: u8 = 0x18;
+const WDT_RESET_16MS: u8 = 0x08;
+
+/// Writes to the UART by polling, the TX buffer is not used.
+struct PanicWriter<'a>(&'a USART0);
+
+impl ufmt::uWrite for PanicWriter<'_> {
+    type Error = Infallible;
+
+    fn write_str(&mut self, string: &str) -> Result<(), Infallible> {
+        for byte in string.bytes() {
+            while self.0.ucsr0a.read().udre0().bit_is_clear() {}
+            // SAFETY:
+            // Any byte is a valid value for the data register.
+            self.0.udr0.write(|w| unsafe { w.bits(byte) });
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    avr_device::interrupt::disable();
+
+    // SAFETY:
+    // The interrupts are off and this function never returns,
+    // so nothing else will access the peripherals anymore.
+    let dp = unsafe { hal::Peripherals::steal() };
+
+    // Stop the step timer and release the motor.
+    dp.TC1.timsk1.reset();
+    dp.TC1.tccr1b.reset();
+    board::release_motor(&dp);
+
+    let (file, line) = info
+        .location()
+        .map_or(("?", 0), |location| (location.file(), location.line()));
+
+    let (address, record) = eq_control::eeprom::encode_panic(file, line);
+    eeprom::write_blocking(address, &record, &dp.EEPROM);
+
+    ufmt::uwriteln!(PanicWriter(&dp.USART0), "\nPANIC at {}:{}!", file, line).ok();
+
+    // Reset in a controlled way. The watchdog may not be running yet.
+    // SAFETY:
+    // The values follow the timed sequence of the datasheet.
+    dp.WDT
+        .wdtcsr
+        .write(|w| unsafe { w.bits(WDT_CHANGE_ENABLE) });
+    dp.WDT.wdtcsr.write(|w| unsafe { w.bits(WDT_RESET_16MS) });
+    loop {}
+}
@@ -0,0 +1,34 @@
+//! Measures the supply voltage with the ADC. The battery is connected to ADC0
+//! (A0 of both boards) through a voltage divider of 10 kΩ and 3.3 kΩ, so up to
+//! 20 V can be measured against the 5 V reference.
+
+use crate::board::{hal, SupplyPin};
+use eq_control::hardware::SupplyVoltage;
+use hal::Adc;
+
+/// The divider scales the supply down by (R1 + R2) / R2.
+const DIVIDER_R1: u32 = 10_000;
+const DIVIDER_R2: u32 = 3_300;
+const REFERENCE_MV: u32 = 5_000;
+/// The supply voltage at the largest reading of the 10 bit ADC.
+const FULL_SCALE_MV: u32 = REFERENCE_MV * (DIVIDER_R1 + DIVIDER_R2) / DIVIDER_R2;
+const ADC_MAX: u32 = 1023;
+
+pub struct Supply {
+    adc: Adc,
+    pin: SupplyPin,
+}
+
+impl Supply {
+    pub fn new(adc: Adc, pin: SupplyPin) -> Self {
+        Self { adc, pin }
+    }
+}
+
+impl SupplyVoltage for Supply {
+    fn millivolts(&mut self) -> Option<u16> {
+        // The first call starts the conversion, one of the next ones gets its result.
+        let reading = self.adc.read_nonblocking(&self.pin).ok()?;
+        Some((reading as u32 * FULL_SCALE_MV / ADC_MAX) as u16)
+    }
+}
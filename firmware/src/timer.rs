@@ -3,11 +3,23 @@
 //! of the timer is reached. It will also reset the timer automatically. The
 //! prescaler will ensure that only every 64-th clock tick the timer will actually
 //! increase in value, resulting in a maximum time period of 0.26214 seconds.
+//!
+//! Every rising edge of the step pin is a step of the motor. The ISR counts the
+//! steps in the current direction, so the position of the platform is known.
+//! A closed end switch holds back the steps towards it, the steps away from
+//! it go on. The held back step is latched, so the controller raises a fault.
 
-use crate::TIMER_STRUCTURE;
-use atmega328p_hal::prelude::*;
+#[cfg(feature = "tmc")]
+use crate::board::{hal, DriverRxPin, DriverTxPin};
+#[cfg(feature = "tmc")]
+use crate::driver_uart::SoftUart;
+use crate::{TimerStructure, TIMER_STRUCTURE};
 use core::ops::DerefMut;
 use embedded_time::duration::*;
+#[cfg(feature = "tmc")]
+use eq_control::hardware::DriverUart;
+use eq_control::hardware::{StepTimer, StepperOutput};
+use eq_control::state_machine::Direction;
 
 pub fn init() {
     avr_device::interrupt::free(|cs| {
@@ -41,12 +53,79 @@ pub fn set_timer_status(active: bool) {
             } else {
                 tmr1.timsk1.write(|w| w.ocie1a().clear_bit());
                 // When we disable the timer, we also want to ensure that the pin is set to low.
-                timer_struct.pin.set_low().void_unwrap();
+                timer_struct.pin.set_low();
             }
         }
     });
 }
 
+pub fn set_direction(direction: Direction) {
+    with_timer_struct(|timer_struct| timer_struct.driver.set_direction(direction));
+}
+
+/// Inverts the direction pin. The position is still counted in the direction of the platform.
+pub fn set_reversed(reversed: bool) {
+    with_timer_struct(|timer_struct| timer_struct.driver.set_reversed(reversed));
+}
+
+/// Sets the mode pins M0 to M2 of the DRV8825.
+pub fn set_microsteps(microsteps: u8) {
+    with_timer_struct(|timer_struct| timer_struct.driver.set_microsteps(microsteps));
+}
+
+/// The LED is lit while the driver is enabled.
+pub fn set_enabled(enabled: bool) {
+    with_timer_struct(|timer_struct| {
+        timer_struct.driver.set_enabled(enabled);
+        if enabled {
+            timer_struct.led_pin.set_high();
+        } else {
+            timer_struct.led_pin.set_low();
+        }
+    });
+}
+
+/// The pins are shared with the ISR, so they are only touched in a critical section.
+fn with_timer_struct(f: impl FnOnce(&mut TimerStructure)) {
+    avr_device::interrupt::free(|cs| {
+        if let Some(ref mut timer_struct) = TIMER_STRUCTURE.borrow(cs).borrow_mut().deref_mut() {
+            f(timer_struct);
+        }
+    });
+}
+
+/// Only the end switch in the direction of travel counts.
+fn at_limit(timer_struct: &mut TimerStructure) -> bool {
+    let direction = timer_struct.driver.direction();
+    timer_struct.end_switches.closed(direction)
+}
+
+/// Returns the number of steps done since startup.
+pub fn get_position() -> i32 {
+    avr_device::interrupt::free(|cs| match TIMER_STRUCTURE.borrow(cs).borrow().as_ref() {
+        Some(timer_struct) => timer_struct.position,
+        None => 0,
+    })
+}
+
+pub fn set_position(position: i32) {
+    avr_device::interrupt::free(|cs| {
+        if let Some(ref mut timer_struct) = TIMER_STRUCTURE.borrow(cs).borrow_mut().deref_mut() {
+            timer_struct.position = position;
+        }
+    });
+}
+
+/// Returns whether an end switch held back a step and clears the flag.
+pub fn take_limit_hit() -> bool {
+    avr_device::interrupt::free(
+        |cs| match TIMER_STRUCTURE.borrow(cs).borrow_mut().deref_mut() {
+            Some(timer_struct) => core::mem::take(&mut timer_struct.limit_hit),
+            None => false,
+        },
+    )
+}
+
 pub fn set_duration(duration: Microseconds) {
     avr_device::interrupt::free(|cs| {
         set_duration_in_cs(duration, cs);
@@ -59,7 +138,8 @@ fn set_duration_in_cs(duration: Microseconds, cs: &avr_device::interrupt::Critic
     // The register is exactly 16 bits wide.
     // We first divide by 4 to convert the microseconds to the binary format
     // and then divide by 2 to achieve a time period of the specified time.
-    let compare_value: u16 = (time / 8) as u16;
+    // Longer times than `MAX_STEP_TIME` saturate instead of wrapping around.
+    let compare_value = (time / 8).min(u16::MAX as u32) as u16;
 
     if let Some(ref mut timer_struct) = TIMER_STRUCTURE.borrow(cs).borrow_mut().deref_mut() {
         timer_struct
@@ -69,16 +149,82 @@ fn set_duration_in_cs(duration: Microseconds, cs: &avr_device::interrupt::Critic
     }
 }
 
-#[avr_device::interrupt(atmega328p)]
+/// The motor carries out the actions requested by the state machine.
+/// A TMC driver is set up over its UART in addition to the pins.
+pub struct Motor {
+    #[cfg(feature = "tmc")]
+    pub uart: SoftUart<DriverTxPin, DriverRxPin, hal::Delay>,
+}
+
+impl StepperOutput for Motor {
+    fn set_direction(&mut self, direction: Direction) {
+        set_direction(direction);
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        set_enabled(enabled);
+    }
+
+    fn set_microsteps(&mut self, microsteps: u8) {
+        set_microsteps(microsteps);
+    }
+
+    fn set_reversed(&mut self, reversed: bool) {
+        set_reversed(reversed);
+    }
+
+    fn position(&self) -> i32 {
+        get_position()
+    }
+
+    fn set_position(&mut self, position: i32) {
+        set_position(position);
+    }
+
+    fn take_limit_hit(&mut self) -> bool {
+        take_limit_hit()
+    }
+}
+
+impl StepTimer for Motor {
+    fn set_step_time(&mut self, time: Microseconds) {
+        set_duration(time);
+    }
+
+    fn set_stepping(&mut self, active: bool) {
+        set_timer_status(active);
+    }
+}
+
+#[cfg(feature = "tmc")]
+impl DriverUart for Motor {
+    fn send(&mut self, datagram: &[u8]) {
+        self.uart.send(datagram);
+    }
+
+    fn transfer(&mut self, request: &[u8], reply: &mut [u8]) -> bool {
+        self.uart.transfer(request, reply)
+    }
+}
+
+#[cfg_attr(feature = "board-uno", avr_device::interrupt(atmega328p))]
+#[cfg_attr(feature = "board-mega2560", avr_device::interrupt(atmega2560))]
 fn TIMER1_COMPA() {
     avr_device::interrupt::free(|cs| {
         if let Some(ref mut timer_struct) = TIMER_STRUCTURE.borrow(cs).borrow_mut().deref_mut() {
             if timer_struct.pin_is_high {
-                timer_struct.pin.set_low().void_unwrap();
+                timer_struct.pin.set_low();
                 timer_struct.pin_is_high = false;
+            } else if at_limit(timer_struct) {
+                timer_struct.limit_hit = true;
             } else {
-                timer_struct.pin.set_high().void_unwrap();
+                timer_struct.pin.set_high();
                 timer_struct.pin_is_high = true;
+
+                match timer_struct.driver.direction() {
+                    Direction::Forward => timer_struct.position += 1,
+                    Direction::Backward => timer_struct.position -= 1,
+                }
             }
         }
     });
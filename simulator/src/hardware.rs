@@ -0,0 +1,279 @@
+//! Simulated implementations of the hardware traits. The simulated time,
+//! the motor and the EEPROM are shared with the main loop of the simulator,
+//! so they survive a reset of the simulated chip.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use embedded_time::duration::*;
+use eq_control::auth::{self, Key};
+use eq_control::hardware::*;
+use eq_control::state_machine::Direction;
+use eq_control::tmc::RegisterModel;
+
+use crate::pty::Pty;
+
+/// The size of the EEPROM of the Atmega328p.
+pub const EEPROM_SIZE: usize = 1024;
+
+pub struct Simulator;
+
+impl Hardware for Simulator {
+    type Motor = SimMotor;
+    type Storage = SimStorage;
+    type Serial = SimSerial;
+    type Clock = SimClock;
+    type Supply = SimSupply;
+
+    /// The simulator guards no real chip, its key is no secret.
+    const BOOTLOADER_KEY: Key = auth::parse_key("000102030405060708090A0B0C0D0E0F");
+}
+
+pub struct MotorState {
+    pub direction: Direction,
+    pub step_time: Microseconds,
+    pub stepping: bool,
+    pub enabled: bool,
+    pub microsteps: u8,
+    /// The simulated motor turns the other way round, the position is not affected.
+    pub reversed: bool,
+    pub position: i32,
+    /// The registers of a TMC2209 on the driver UART.
+    pub driver: RegisterModel,
+    /// The time since the last step.
+    elapsed: u64,
+}
+
+impl Default for MotorState {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Forward,
+            step_time: Microseconds(0),
+            stepping: false,
+            enabled: true,
+            microsteps: 32,
+            reversed: false,
+            position: 0,
+            driver: RegisterModel::default(),
+            elapsed: 0,
+        }
+    }
+}
+
+impl MotorState {
+    /// Does all the steps that happen in the given time.
+    pub fn advance(&mut self, micros: u64) {
+        let period = step_period(self.step_time);
+        if !self.stepping || !self.enabled || period == 0 {
+            self.elapsed = 0;
+            return;
+        }
+
+        self.elapsed += micros;
+        let steps = (self.elapsed / period) as i32;
+        self.elapsed %= period;
+
+        match self.direction {
+            Direction::Forward => self.position += steps,
+            Direction::Backward => self.position -= steps,
+        }
+    }
+}
+
+/// The firmware toggles the step pin on every compare match of a timer that
+/// counts in 4us ticks, so a whole step takes twice the compare period.
+/// The compare register is only 16 bits wide.
+fn step_period(step_time: Microseconds) -> u64 {
+    let compare_value = (*step_time.integer() / 8) as u16;
+    (compare_value as u64 + 1) * 8
+}
+
+pub struct SimMotor(pub Rc<RefCell<MotorState>>);
+
+impl StepperOutput for SimMotor {
+    fn set_direction(&mut self, direction: Direction) {
+        self.0.borrow_mut().direction = direction;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.0.borrow_mut().enabled = enabled;
+    }
+
+    fn set_microsteps(&mut self, microsteps: u8) {
+        self.0.borrow_mut().microsteps = microsteps;
+    }
+
+    fn set_reversed(&mut self, reversed: bool) {
+        self.0.borrow_mut().reversed = reversed;
+    }
+
+    fn position(&self) -> i32 {
+        self.0.borrow().position
+    }
+
+    fn set_position(&mut self, position: i32) {
+        self.0.borrow_mut().position = position;
+    }
+
+    /// The simulated platform has no end switches.
+    fn take_limit_hit(&mut self) -> bool {
+        false
+    }
+}
+
+impl StepTimer for SimMotor {
+    fn set_step_time(&mut self, time: Microseconds) {
+        self.0.borrow_mut().step_time = time;
+    }
+
+    fn set_stepping(&mut self, active: bool) {
+        self.0.borrow_mut().stepping = active;
+    }
+}
+
+impl DriverUart for SimMotor {
+    fn send(&mut self, datagram: &[u8]) {
+        self.0.borrow_mut().driver.send(datagram);
+    }
+
+    fn transfer(&mut self, request: &[u8], reply: &mut [u8]) -> bool {
+        self.0.borrow_mut().driver.transfer(request, reply)
+    }
+}
+
+/// The simulated EEPROM. When a file is given, every write is stored in it.
+pub struct SimStorage {
+    data: Rc<RefCell<Vec<u8>>>,
+    file: Option<PathBuf>,
+}
+
+impl SimStorage {
+    pub fn new(data: Rc<RefCell<Vec<u8>>>, file: Option<PathBuf>) -> Self {
+        Self { data, file }
+    }
+
+    /// Loads the EEPROM content from the file. A missing file is an erased EEPROM.
+    pub fn load(file: Option<&PathBuf>) -> Vec<u8> {
+        let mut data = file
+            .and_then(|file| fs::read(file).ok())
+            .unwrap_or_default();
+        data.resize(EEPROM_SIZE, 0xFF);
+        data
+    }
+}
+
+impl Storage for SimStorage {
+    fn read(&self, address: u16, buffer: &mut [u8]) {
+        let address = address as usize;
+        buffer.copy_from_slice(&self.data.borrow()[address..address + buffer.len()]);
+    }
+
+    fn write(&mut self, address: u16, data: &[u8]) {
+        let address = address as usize;
+        self.data.borrow_mut()[address..address + data.len()].copy_from_slice(data);
+
+        if let Some(ref file) = self.file {
+            if let Err(error) = fs::write(file, &*self.data.borrow()) {
+                eprintln!("Could not write the EEPROM file: {}", error);
+            }
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        false
+    }
+}
+
+pub struct SimSerial {
+    pty: Rc<Pty>,
+    received: VecDeque<u8>,
+}
+
+impl SimSerial {
+    pub fn new(pty: Rc<Pty>) -> Self {
+        Self {
+            pty,
+            received: VecDeque::new(),
+        }
+    }
+}
+
+impl SerialPort for SimSerial {
+    /// The pseudo-terminal has no line errors.
+    fn read_byte(&mut self) -> Option<Result<u8, RxError>> {
+        if self.received.is_empty() {
+            let mut buffer = [0; 64];
+            let count = self.pty.read(&mut buffer);
+            self.received.extend(&buffer[..count]);
+        }
+        self.received.pop_front().map(Ok)
+    }
+
+    fn write_str(&mut self, string: &str) {
+        self.pty.write(string.as_bytes());
+    }
+}
+
+/// The simulated time in microseconds. The clock starts at zero on every boot.
+pub struct SimClock {
+    time: Rc<Cell<u64>>,
+    boot_time: u64,
+}
+
+impl SimClock {
+    pub fn new(time: Rc<Cell<u64>>) -> Self {
+        let boot_time = time.get();
+        Self { time, boot_time }
+    }
+}
+
+impl Clock for SimClock {
+    fn millis(&self) -> u32 {
+        ((self.time.get() - self.boot_time) / 1000) as u32
+    }
+}
+
+/// A battery that never runs flat.
+pub struct SimSupply(pub u16);
+
+impl SupplyVoltage for SimSupply {
+    fn millivolts(&mut self) -> Option<u16> {
+        Some(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motor_steps_with_the_timer_period() {
+        let mut motor = MotorState {
+            step_time: Microseconds(1200),
+            stepping: true,
+            ..MotorState::default()
+        };
+
+        // 1200us result in a compare value of 150, so a step takes 1208us.
+        motor.advance(1208 * 10 + 100);
+        assert_eq!(motor.position, 10);
+
+        motor.direction = Direction::Backward;
+        motor.advance(1108);
+        assert_eq!(motor.position, 9);
+    }
+
+    #[test]
+    fn motor_stands_still_when_not_stepping() {
+        let mut motor = MotorState {
+            step_time: Microseconds(1200),
+            ..MotorState::default()
+        };
+
+        motor.advance(1_000_000);
+        assert_eq!(motor.position, 0);
+    }
+}
@@ -0,0 +1,208 @@
+//! Runs the control logic of the firmware on the host. The serial interface
+//! shows up as a pseudo-terminal, so the INDI driver, PHD2 or a terminal
+//! program can be used without the hardware on the desk.
+//!
+//! Usage: eq-simulator [--speed FACTOR] [--eeprom FILE] [--link PATH] [--log-interval SECONDS]
+//!                     [--supply MILLIVOLTS]
+//!        eq-simulator --respond KEY CHALLENGE
+//!
+//! `--respond` only prints the answer to a bootloader challenge of the firmware
+//! built with the key, see `eq_control::auth`. The simulator itself uses the
+//! key 000102030405060708090A0B0C0D0E0F.
+
+mod hardware;
+mod pty;
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use embedded_time::duration::*;
+use eq_control::auth::{self, Key};
+use eq_control::reset::ResetCause;
+use eq_control::{Control, Controller};
+
+use crate::hardware::*;
+use crate::pty::Pty;
+
+/// The real time between two polls of the controller.
+const TICK: Duration = Duration::from_millis(1);
+
+struct Options {
+    /// How much faster than real time the simulated time runs.
+    speed: u64,
+    eeprom: Option<PathBuf>,
+    link: Option<PathBuf>,
+    log_interval: u64,
+    /// The voltage of the simulated battery.
+    supply: u16,
+    /// The key and the bootloader challenge to answer instead of simulating.
+    respond: Option<(Key, u64)>,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut options = Options {
+            speed: 1,
+            eeprom: None,
+            link: None,
+            log_interval: 10,
+            supply: 12_600,
+            respond: None,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+
+            match arg.as_str() {
+                "--speed" => options.speed = parse_number(&value()?)?,
+                "--eeprom" => options.eeprom = Some(PathBuf::from(value()?)),
+                "--link" => options.link = Some(PathBuf::from(value()?)),
+                "--log-interval" => options.log_interval = parse_number(&value()?)?,
+                "--supply" => {
+                    let supply = value()?;
+                    options.supply = supply
+                        .parse()
+                        .map_err(|_| format!("Invalid voltage {}", supply))?;
+                }
+                "--respond" => {
+                    let key = parse_key(&value()?)?;
+                    let challenge = value()?;
+                    let challenge = u64::from_str_radix(&challenge, 16)
+                        .map_err(|_| format!("Invalid challenge {}", challenge))?;
+                    options.respond = Some((key, challenge));
+                }
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+
+        if options.speed == 0 {
+            return Err("The speed has to be at least 1".into());
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number {}", value))
+}
+
+fn parse_key(hex: &str) -> Result<Key, String> {
+    if hex.len() == 2 * auth::KEY_LENGTH && hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        Ok(auth::parse_key(hex))
+    } else {
+        Err(format!("The key has to be 32 hex digits, not {}", hex))
+    }
+}
+
+/// A different seed for the bootloader challenges on every start.
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn main() {
+    let options = Options::parse().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        eprintln!(
+            "Usage: eq-simulator [--speed FACTOR] [--eeprom FILE] \
+             [--link PATH] [--log-interval SECONDS] [--supply MILLIVOLTS]\n       \
+             eq-simulator --respond KEY CHALLENGE"
+        );
+        process::exit(1);
+    });
+
+    if let Some((key, challenge)) = options.respond {
+        println!("bootloader={:016X}", auth::response(&key, challenge));
+        return;
+    }
+
+    let pty = Rc::new(Pty::open().unwrap_or_else(|error| {
+        eprintln!("Could not open a pseudo-terminal: {}", error);
+        process::exit(1);
+    }));
+    println!("Serial port of the simulated platform: {}", pty.name());
+
+    if let Some(ref link) = options.link {
+        std::fs::remove_file(link).ok();
+        match std::os::unix::fs::symlink(pty.name(), link) {
+            Ok(()) => println!("Linked to {}", link.display()),
+            Err(error) => eprintln!("Could not create the link: {}", error),
+        }
+    }
+
+    let time = Rc::new(Cell::new(0_u64));
+    let eeprom = Rc::new(RefCell::new(SimStorage::load(options.eeprom.as_ref())));
+    let tick = TICK.as_micros() as u64 * options.speed;
+    let log_interval = options.log_interval * 1_000_000;
+
+    // Every iteration of this loop is a boot of the simulated chip.
+    let mut reset_cause = ResetCause::PowerOn;
+    loop {
+        let motor = Rc::new(RefCell::new(MotorState::default()));
+        let mut controller = Controller::<Simulator>::new(
+            SimMotor(motor.clone()),
+            SimStorage::new(eeprom.clone(), options.eeprom.clone()),
+            SimSerial::new(pty.clone()),
+            SimClock::new(time.clone()),
+            SimSupply(options.supply),
+            reset_cause,
+            random_seed(),
+        );
+        let mut last_log = time.get();
+
+        loop {
+            thread::sleep(TICK);
+            time.set(time.get() + tick);
+            motor.borrow_mut().advance(tick);
+
+            match controller.poll() {
+                Control::Continue => {}
+                Control::Reset => {
+                    println!("[{:>10.3} s] Reset", seconds(time.get()));
+                    // The firmware lets the watchdog starve.
+                    reset_cause = ResetCause::Watchdog;
+                    break;
+                }
+                Control::Bootloader => {
+                    // There is no firmware to update, the bootloader times out and
+                    // starts it again. The jump leaves no reset flag.
+                    println!("[{:>10.3} s] Bootloader", seconds(time.get()));
+                    reset_cause = ResetCause::Unknown;
+                    break;
+                }
+            }
+
+            if time.get() - last_log >= log_interval {
+                last_log = time.get();
+
+                let motor = motor.borrow();
+                println!(
+                    "[{:>10.3} s] position: {:>8} steps, {}, step time: {}us",
+                    seconds(time.get()),
+                    motor.position,
+                    if !motor.enabled {
+                        "disabled"
+                    } else if motor.stepping {
+                        "stepping"
+                    } else {
+                        "stopped"
+                    },
+                    motor.step_time.integer(),
+                );
+            }
+        }
+    }
+}
+
+fn seconds(micros: u64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
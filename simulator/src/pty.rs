@@ -0,0 +1,90 @@
+//! A pseudo-terminal that looks like the serial port of the platform
+//! to the INDI driver, PHD2 or a terminal program.
+
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::RawFd;
+
+pub struct Pty {
+    master: RawFd,
+    slave: RawFd,
+    name: String,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        // SAFETY:
+        // Plain libc calls on file descriptors owned by this struct.
+        unsafe {
+            let master = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            check(libc::grantpt(master))?;
+            check(libc::unlockpt(master))?;
+
+            let mut name = [0 as libc::c_char; 64];
+            if libc::ptsname_r(master, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Keep a handle of the slave side open. Otherwise the master side
+            // reports a hang up as long as no client is connected.
+            let slave = check(libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY))?;
+
+            // A real serial port neither echoes nor translates line endings.
+            let mut termios = std::mem::zeroed();
+            check(libc::tcgetattr(slave, &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(slave, libc::TCSANOW, &termios))?;
+
+            let flags = check(libc::fcntl(master, libc::F_GETFL))?;
+            check(libc::fcntl(master, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+
+            Ok(Self {
+                master,
+                slave,
+                name: CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned(),
+            })
+        }
+    }
+
+    /// The path of the device the clients have to open.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reads the available bytes without blocking.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        // SAFETY:
+        // The buffer is valid for its whole length.
+        let count = unsafe { libc::read(self.master, buffer.as_mut_ptr().cast(), buffer.len()) };
+        count.max(0) as usize
+    }
+
+    /// Writes the data. If no client reads the output and the
+    /// buffer of the terminal is full, the data is dropped.
+    pub fn write(&self, data: &[u8]) {
+        // SAFETY:
+        // The data is valid for its whole length.
+        unsafe {
+            libc::write(self.master, data.as_ptr().cast(), data.len());
+        }
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        // SAFETY:
+        // The file descriptors are owned by this struct and not used afterwards.
+        unsafe {
+            libc::close(self.slave);
+            libc::close(self.master);
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}